@@ -0,0 +1,396 @@
+use crate::config::RepositoryConfig;
+use crate::crawl::{CrawlReport, Crawler};
+use crate::daemon::run_daemon;
+use crate::db::{InvalidReason, InvalidVersion, Store};
+use crate::github::GithubSource;
+use crate::limits::Limits;
+use crate::manager::{LocalPublisher, Publisher, SoldeerPublisher};
+use crate::npm::NpmSource;
+use crate::schedule::Schedule;
+use crate::shutdown::Shutdown;
+use crate::source::Source;
+use crate::utils::format_time;
+use crate::version::{TagParser, VersionFilter};
+use chrono::{Duration, Utc};
+use clap::ValueEnum;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub const EXIT_SUCCESS: i32 = 0;
+// the command ran but something failed, e.g. a repository couldn't be crawled
+pub const EXIT_FAILURE: i32 = 1;
+// the command couldn't run because of its arguments or of repositories.toml
+pub const EXIT_INVALID: i32 = 2;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Npm,
+    Github,
+    // every source, one after the other
+    All,
+}
+
+impl Target {
+    fn sources(&self) -> Vec<Box<dyn Source>> {
+        match self {
+            Target::Npm => vec![Box::new(NpmSource::new())],
+            Target::Github => vec![Box::new(GithubSource::new())],
+            Target::All => vec![Box::new(NpmSource::new()), Box::new(GithubSource::new())],
+        }
+    }
+}
+
+// SOLDEER_PUBLISH_DIR lets a crawl write archives locally instead of pushing to the registry
+fn publisher() -> Box<dyn Publisher> {
+    match env::var("SOLDEER_PUBLISH_DIR") {
+        Ok(directory) => Box::new(LocalPublisher {
+            directory: PathBuf::from(directory),
+        }),
+        Err(_) => Box::new(SoldeerPublisher),
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CrawlOptions {
+    /// Only crawl this repository, can be repeated
+    #[arg(long = "repo", value_name = "REPO")]
+    pub repositories: Vec<String>,
+
+    /// Crawl the repositories even if they are not due
+    #[arg(long)]
+    pub force: bool,
+
+    /// Default time between two checks of a repository, e.g. `15m`, `1h` or `7d`
+    #[arg(long)]
+    pub interval: Option<String>,
+
+    /// How many repositories are crawled at the same time
+    #[arg(long)]
+    pub concurrency: Option<usize>,
+
+    /// Only show what would be published, without publishing or recording anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Download and extract the versions a dry run would publish, leaving them on disk for review
+    #[arg(long, requires = "dry_run")]
+    pub download: bool,
+
+    /// Write what a dry run would publish to this file as JSON
+    #[arg(long, value_name = "FILE", requires = "dry_run")]
+    pub json: Option<PathBuf>,
+}
+
+// Crawls the due repositories of `target` once, or only the ones given with `--repo`
+pub async fn crawl(
+    store: &Store,
+    target: Target,
+    options: CrawlOptions,
+    shutdown: &Arc<Shutdown>,
+) -> i32 {
+    let schedule = match Schedule::load(options.interval) {
+        Ok(schedule) => schedule,
+        Err(err) => {
+            eprintln!("Err {}", err);
+            return EXIT_INVALID;
+        }
+    };
+    let limits = match Limits::load(options.concurrency, shutdown.clone()) {
+        Ok(limits) => limits,
+        Err(err) => {
+            eprintln!("Err {}", err);
+            return EXIT_INVALID;
+        }
+    };
+    let publisher = publisher();
+    let crawler = Crawler {
+        store,
+        publisher: publisher.as_ref(),
+        schedule: &schedule,
+        shutdown,
+        limits: &limits,
+        dry_run: options.dry_run,
+        download: options.download,
+    };
+    let only = &options.repositories;
+    let sources = target.sources();
+
+    let mut selected: Vec<Vec<RepositoryConfig>> = Vec::new();
+    let mut unknown: Vec<&String> = only.iter().collect();
+    for source in sources.iter() {
+        let repositories: Vec<RepositoryConfig> = match source.load_repositories() {
+            Ok(repositories) => repositories,
+            Err(err) => {
+                eprintln!("Err {}", err);
+                return EXIT_INVALID;
+            }
+        };
+        unknown.retain(|repo| !repositories.iter().any(|r| &&r.repo == repo));
+        selected.push(
+            repositories
+                .into_iter()
+                .filter(|repository| only.is_empty() || only.contains(&repository.repo))
+                .collect(),
+        );
+    }
+    if !unknown.is_empty() {
+        for repo in unknown {
+            eprintln!("Unknown repository {}", repo);
+        }
+        return EXIT_INVALID;
+    }
+
+    let mut report = CrawlReport::default();
+    let sources: Vec<(&dyn Source, &[RepositoryConfig])> = sources
+        .iter()
+        .zip(selected.iter())
+        .map(|(source, repositories)| (source.as_ref(), repositories.as_slice()))
+        .collect();
+    crawler
+        .crawl_due_repositories(&sources, options.force, &mut report)
+        .await;
+
+    report.print_summary();
+    if let Some(json) = options.json {
+        let written = serde_json::to_string_pretty(&report.planned)
+            .map_err(|err| err.to_string())
+            .and_then(|plan| fs::write(&json, plan).map_err(|err| err.to_string()));
+        if let Err(err) = written {
+            eprintln!("Error writing the plan to {}: {}", json.display(), err);
+            return EXIT_FAILURE;
+        }
+    }
+    if report.is_success() {
+        EXIT_SUCCESS
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+pub async fn daemon(
+    store: &Store,
+    interval: Option<String>,
+    concurrency: Option<usize>,
+    shutdown: &Arc<Shutdown>,
+) -> i32 {
+    let publisher = publisher();
+    match run_daemon(
+        store,
+        &Target::All.sources(),
+        publisher.as_ref(),
+        interval,
+        concurrency,
+        shutdown,
+    )
+    .await
+    {
+        Ok(_) => EXIT_SUCCESS,
+        Err(err) => {
+            eprintln!("Err {}", err);
+            EXIT_INVALID
+        }
+    }
+}
+
+pub fn list_versions(store: &Store, repository: &str) -> i32 {
+    let versions = match store.list_versions(repository) {
+        Ok(versions) => versions,
+        Err(err) => {
+            eprintln!("Error reading versions: {}", err);
+            return EXIT_FAILURE;
+        }
+    };
+    let invalid_versions = match store.list_invalid_versions(repository) {
+        Ok(versions) => versions,
+        Err(err) => {
+            eprintln!("Error reading invalid versions: {}", err);
+            return EXIT_FAILURE;
+        }
+    };
+
+    println!("{} published versions", versions.len());
+    for version in versions {
+        println!(
+            "  {}  {}{}",
+            version.version,
+            version.last_updated.to_rfc3339(),
+            tag_suffix(&version.tag)
+        );
+    }
+    println!("{} invalid versions", invalid_versions.len());
+    for record in invalid_versions {
+        let invalid_version = record.invalid_version;
+        let next_attempt = match record.next_attempt_at {
+            Some(next_attempt_at) => format!("retrying at {}", next_attempt_at.to_rfc3339()),
+            None => "quarantined".to_string(),
+        };
+        println!(
+            "  {}{}  {} after {} attempts, {}: {}",
+            invalid_version.version,
+            tag_suffix(&invalid_version.tag),
+            invalid_version.reason.as_str(),
+            record.attempts,
+            next_attempt,
+            invalid_version.error
+        );
+    }
+    EXIT_SUCCESS
+}
+
+// ` (tag v1.2.3)` for versions parsed from a git tag
+fn tag_suffix(tag: &Option<String>) -> String {
+    match tag {
+        Some(tag) => format!(" (tag {})", tag),
+        None => String::new(),
+    }
+}
+
+pub fn mark_invalid(store: &Store, repository: &str, version: &str, error: Option<String>) -> i32 {
+    let invalid_version = InvalidVersion {
+        repository: repository.to_string(),
+        version: version.to_string(),
+        tag: None,
+        reason: InvalidReason::Manual,
+        error: error.unwrap_or("marked invalid by hand".to_string()),
+        last_updated: Utc::now(),
+    };
+    match store.insert_invalid_version(invalid_version) {
+        Ok(_) => EXIT_SUCCESS,
+        Err(err) => {
+            eprintln!("Error marking {} {} invalid: {}", repository, version, err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+pub fn retry(store: &Store, repository: &str, version: &str) -> i32 {
+    match store.retry_invalid_version(repository, version) {
+        Ok(true) => {
+            println!("{} {} will be crawled on the next run", repository, version);
+            EXIT_SUCCESS
+        }
+        Ok(false) => {
+            eprintln!("{} {} is not an invalid version", repository, version);
+            EXIT_FAILURE
+        }
+        Err(err) => {
+            eprintln!("Error retrying {} {}: {}", repository, version, err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+// Prints the crawl state of the configured repositories, or only of `repository`
+pub fn status(store: &Store, repository: Option<&str>) -> i32 {
+    let mut code = EXIT_SUCCESS;
+    for source in Target::All.sources() {
+        let repositories = match source.load_repositories() {
+            Ok(repositories) => repositories,
+            Err(err) => {
+                eprintln!("Err {}", err);
+                return EXIT_INVALID;
+            }
+        };
+        for config in repositories
+            .iter()
+            .filter(|config| repository.is_none_or(|repository| config.repo == repository))
+        {
+            let state = match store.get_repository_state(&config.repo) {
+                Ok(state) => state,
+                Err(err) => {
+                    eprintln!("Error reading the state of {}: {}", config.repo, err);
+                    code = EXIT_FAILURE;
+                    continue;
+                }
+            };
+            let versions = store.list_versions(&config.repo).map(|v| v.len());
+            let invalid_versions = store.list_invalid_versions(&config.repo).map(|v| v.len());
+            let (Ok(versions), Ok(invalid_versions)) = (versions, invalid_versions) else {
+                eprintln!("Error reading the versions of {}", config.repo);
+                code = EXIT_FAILURE;
+                continue;
+            };
+            println!(
+                "{} {}: {} published, {} invalid, last checked {}, last success {}, next check {}",
+                source.name(),
+                config.repo,
+                versions,
+                invalid_versions,
+                format_time(state.last_checked_at),
+                format_time(state.last_success_at),
+                format_time(state.next_check_at)
+            );
+            if state.consecutive_failures > 0 {
+                println!(
+                    "  {} consecutive failures: {}",
+                    state.consecutive_failures,
+                    state.last_error.unwrap_or_default()
+                );
+            }
+        }
+    }
+    code
+}
+
+// Checks every setting of repositories.toml, reporting all the problems at once
+pub fn validate_config() -> i32 {
+    let mut problems: Vec<String> = Vec::new();
+    if let Err(err) = Schedule::load(None) {
+        problems.push(err);
+    }
+    if let Err(err) = Limits::load(None, Arc::default()) {
+        problems.push(err);
+    }
+    for source in Target::All.sources() {
+        let repositories = match source.load_repositories() {
+            Ok(repositories) => repositories,
+            Err(err) => {
+                problems.push(format!("loading {} repositories: {}", source.name(), err));
+                continue;
+            }
+        };
+        let mut repos: HashSet<String> = HashSet::new();
+        let mut names: HashSet<String> = HashSet::new();
+        for repository in repositories.iter() {
+            let name = source.dependency_name(repository);
+            if !repos.insert(repository.repo.clone()) {
+                problems.push(format!("{} is listed twice", repository.repo));
+            } else if !names.insert(name.clone()) {
+                problems.push(format!(
+                    "{} is published as {} like another repository",
+                    repository.repo, name
+                ));
+            }
+            if let Err(err) = TagParser::new(repository) {
+                problems.push(format!(
+                    "invalid tag_pattern for {}: {}",
+                    repository.repo, err
+                ));
+            }
+            if let Err(err) = VersionFilter::new(repository) {
+                problems.push(format!("{} for {}", err, repository.repo));
+            }
+            // checked even when the global intervals are broken, the default doesn't matter here
+            if let Err(err) = repository.interval(Duration::zero()) {
+                problems.push(err);
+            }
+        }
+        println!(
+            "Checked {} {} repositories",
+            repositories.len(),
+            source.name()
+        );
+    }
+
+    if problems.is_empty() {
+        println!("repositories.toml is valid");
+        return EXIT_SUCCESS;
+    }
+    for problem in problems.iter() {
+        eprintln!("  {}", problem);
+    }
+    EXIT_INVALID
+}
@@ -0,0 +1,302 @@
+use crate::source::LoadError;
+use crate::utils::{get_current_working_dir, read_file_to_string};
+use chrono::Duration;
+use serde_derive::Deserialize;
+use sha1::{Digest, Sha1};
+
+// how long a repository is left alone after being checked, unless configured otherwise
+pub const DEFAULT_INTERVAL: &str = "1h";
+// the longest a repository that stopped releasing is left alone
+pub const DEFAULT_MAX_INTERVAL: &str = "7d";
+// repositories crawled at the same time
+pub const DEFAULT_CONCURRENCY: usize = 8;
+// downloads or pushes running against the same host at the same time
+pub const DEFAULT_HOST_CONCURRENCY: usize = 2;
+// requests sent to the same host every second, on average
+pub const DEFAULT_REQUESTS_PER_SECOND: f64 = 10.0;
+
+/// Settings of repositories.toml shared by every source.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    // default time between two checks of a repository, e.g. `30m`, `1h` or `7d`
+    #[serde(default)]
+    pub interval: Option<String>,
+    // bounds of the interval derived from the release cadence of a repository,
+    // `min_interval` defaults to `interval`
+    #[serde(default)]
+    pub min_interval: Option<String>,
+    #[serde(default)]
+    pub max_interval: Option<String>,
+    // repositories crawled at the same time, `--concurrency` overrides it
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    // downloads or pushes running against the same host at the same time
+    #[serde(default)]
+    pub host_concurrency: Option<usize>,
+    // requests sent to the same host every second, on average
+    #[serde(default)]
+    pub requests_per_second: Option<f64>,
+    // the repositories of every source, only listed so they aren't unknown keys, the sources read
+    // them themselves
+    #[serde(default)]
+    #[allow(dead_code)]
+    pub npm: Option<toml::Value>,
+    #[serde(default)]
+    #[allow(dead_code)]
+    pub github: Option<toml::Value>,
+}
+
+/// A single entry of repositories.toml. Entries can either be a plain string
+/// (`"foundry-rs/forge-std"`) or a table carrying per-repository metadata
+/// (`{ repo = "Uniswap/v4-core", name = "uniswap-v4-core", strategy = ["tags"] }`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "toml::Value")]
+pub enum RepositoryEntry {
+    Plain(String),
+    Detailed(Box<RepositoryConfig>),
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RepositoryConfig {
+    // the repository as known by the source (e.g. `Uniswap/v4-core` or `@openzeppelin/contracts`)
+    pub repo: String,
+    // the dependency name used on Soldeer, derived from `repo` when missing
+    #[serde(default)]
+    pub name: Option<String>,
+    // stripped from tags before looking for a version, e.g. `contracts-`
+    #[serde(default)]
+    pub tag_prefix: Option<String>,
+    // regex whose `version` (or first) capture group is the version, replaces the default parsing
+    #[serde(default)]
+    pub tag_pattern: Option<String>,
+    // where versions are discovered from, tried in order until one of them yields versions
+    #[serde(default)]
+    pub strategy: Option<Vec<VersionStrategy>>,
+    // collect versions from every strategy instead of stopping at the first one that yields any
+    #[serde(default)]
+    pub merge_strategies: bool,
+    // also snapshot the head of `branch`, whatever `strategy` found
+    #[serde(default)]
+    pub snapshot_branch: bool,
+    // branch snapshotted by the `default-branch` strategy and `snapshot_branch`, main/master when
+    // missing
+    #[serde(default)]
+    pub branch: Option<String>,
+    // skip versions with a pre-release part such as `-rc.1`
+    #[serde(default)]
+    pub exclude_prereleases: bool,
+    // skip versions older than this one
+    #[serde(default)]
+    pub min_version: Option<String>,
+    // only keep the newest N versions of every major version
+    #[serde(default)]
+    pub latest_per_major: Option<usize>,
+    // skip versions matching this regex, e.g. `-solc-0\.7`
+    #[serde(default)]
+    pub deny_pattern: Option<String>,
+    // stop listing releases and tags after this many pages of 100, every page when missing
+    #[serde(default)]
+    pub max_pages: Option<u32>,
+    // time between two checks of this repository, overrides the default interval
+    #[serde(default)]
+    pub interval: Option<String>,
+}
+
+impl RepositoryConfig {
+    pub fn strategies(&self) -> Vec<VersionStrategy> {
+        match &self.strategy {
+            Some(strategy) => strategy.clone(),
+            None => vec![VersionStrategy::Releases, VersionStrategy::Tags],
+        }
+    }
+
+    // changes whenever the settings of the repository do, e.g. a lower `min_version`, so that
+    // listings crawled with other settings aren't trusted to be unchanged
+    pub fn fingerprint(&self) -> String {
+        hex::encode(Sha1::digest(format!("{:?}", self)))
+    }
+
+    pub fn interval(&self, default: Duration) -> Result<Duration, String> {
+        match &self.interval {
+            Some(interval) => parse_interval(interval)
+                .ok_or(format!("invalid interval {} for {}", interval, self.repo)),
+            None => Ok(default),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum VersionStrategy {
+    Releases,
+    Tags,
+    // a snapshot of the head commit of the default (or configured) branch
+    DefaultBranch,
+}
+
+// Tables are parsed as a `RepositoryConfig` themselves, so that their errors name the unknown or
+// invalid key instead of not matching any kind of entry
+impl TryFrom<toml::Value> for RepositoryEntry {
+    type Error = toml::de::Error;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        match value {
+            toml::Value::String(repo) => Ok(RepositoryEntry::Plain(repo)),
+            value => Ok(RepositoryEntry::Detailed(Box::new(value.try_into()?))),
+        }
+    }
+}
+
+impl From<RepositoryEntry> for RepositoryConfig {
+    fn from(entry: RepositoryEntry) -> Self {
+        match entry {
+            RepositoryEntry::Plain(repo) => RepositoryConfig {
+                repo,
+                ..Default::default()
+            },
+            RepositoryEntry::Detailed(config) => *config,
+        }
+    }
+}
+
+pub fn load_settings() -> Result<Settings, LoadError> {
+    let filename: String = get_current_working_dir()
+        .unwrap()
+        .join(String::from("repositories.toml"))
+        .to_str()
+        .unwrap()
+        .to_string();
+    let contents = read_file_to_string(filename.clone()).map_err(|err| LoadError {
+        cause: format!("reading {}: {}", filename, err),
+    })?;
+    match toml::from_str(&contents) {
+        Ok(settings) => Ok(settings),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            eprintln!("Unable to load settings from repositories.toml");
+            Err(LoadError {
+                cause: format!("parsing {}: {}", filename, err),
+            })
+        }
+    }
+}
+
+// Parses intervals such as `90s`, `15m`, `1h`, `7d` or `2w`
+pub fn parse_interval(interval: &str) -> Option<Duration> {
+    let interval = interval.trim();
+    let unit = interval.chars().last()?;
+    let amount: i64 = interval[..interval.len() - unit.len_utf8()].parse().ok()?;
+    if amount <= 0 {
+        return None;
+    }
+    match unit {
+        's' => Duration::try_seconds(amount),
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_unit() {
+        assert_eq!(parse_interval("90s"), Some(Duration::seconds(90)));
+        assert_eq!(parse_interval("15m"), Some(Duration::minutes(15)));
+        assert_eq!(parse_interval("1h"), Some(Duration::hours(1)));
+        assert_eq!(parse_interval("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_interval("2w"), Some(Duration::weeks(2)));
+        assert_eq!(parse_interval(" 30m "), Some(Duration::minutes(30)));
+    }
+
+    #[test]
+    fn rejects_invalid_intervals() {
+        for interval in [
+            "1h30m",
+            "0h",
+            "-1h",
+            "h",
+            "",
+            "5x",
+            "1.5h",
+            "1 h",
+            "10",
+            "9999999999999999w",
+        ] {
+            assert_eq!(parse_interval(interval), None, "{:?}", interval);
+        }
+    }
+
+    #[test]
+    fn repository_interval_falls_back_to_the_default() {
+        let mut repository = RepositoryConfig {
+            repo: "Uniswap/v4-core".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            repository.interval(Duration::hours(1)),
+            Ok(Duration::hours(1))
+        );
+        repository.interval = Some("30m".to_string());
+        assert_eq!(
+            repository.interval(Duration::hours(1)),
+            Ok(Duration::minutes(30))
+        );
+        repository.interval = Some("1h30m".to_string());
+        assert!(repository.interval(Duration::hours(1)).is_err());
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Data {
+        github: Vec<RepositoryEntry>,
+    }
+
+    #[test]
+    fn parses_plain_and_detailed_entries() {
+        let data: Data = toml::from_str(
+            r#"github = ["foundry-rs/forge-std", { repo = "Uniswap/v4-core", interval = "1d" }]"#,
+        )
+        .unwrap();
+        let repositories: Vec<RepositoryConfig> = data
+            .github
+            .into_iter()
+            .map(RepositoryConfig::from)
+            .collect();
+        assert_eq!(repositories[0].repo, "foundry-rs/forge-std");
+        assert_eq!(repositories[1].repo, "Uniswap/v4-core");
+        assert_eq!(repositories[1].interval, Some("1d".to_string()));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        for entry in [
+            r#"{ repo = "Uniswap/v4-core", intervl = "1d" }"#,
+            r#"{ repo = "Uniswap/v4-core", tag_prefx = "v" }"#,
+            r#"{ repo = "Uniswap/v4-core", exclude_prerelease = true }"#,
+            r#"{ repo = "Uniswap/v4-core", strip_v_prefix = true }"#,
+        ] {
+            let err = toml::from_str::<Data>(&format!("github = [{}]", entry))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("unknown field"), "{}", err);
+        }
+        let err = toml::from_str::<Settings>("intervl = \"1h\"\ngithub = []")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown field `intervl`"), "{}", err);
+    }
+
+    #[test]
+    fn parses_the_shipped_settings() {
+        let settings: Settings = toml::from_str(include_str!("../repositories.toml")).unwrap();
+        assert_eq!(settings.interval, Some("1h".to_string()));
+        let data: Data = toml::from_str(include_str!("../repositories.toml")).unwrap();
+        assert!(!data.github.is_empty());
+    }
+}
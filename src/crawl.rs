@@ -0,0 +1,504 @@
+use crate::config::RepositoryConfig;
+use crate::db::{InvalidReason, InvalidVersion, Store, Version};
+use crate::limits::{host_of, Limits};
+use crate::manager::{Publisher, PushErrorKind};
+use crate::schedule::Schedule;
+use crate::shutdown::Shutdown;
+use crate::source::{Listing, MaterializeError, Source, Usage, Validators};
+use crate::version::VersionFilter;
+use crate::VersionStruct;
+use chrono::{DateTime, Duration, Utc};
+use futures::stream::{self, StreamExt};
+use serde_derive::Serialize;
+use std::fmt::{self};
+use std::path::PathBuf;
+
+/// Outcome of a crawl, collecting what failed so one broken repository doesn't stop the others.
+#[derive(Debug, Default)]
+pub struct CrawlReport {
+    pub repositories: usize,
+    pub published: Vec<String>,
+    pub failures: Vec<CrawlFailure>,
+    // what a dry run would have published
+    pub planned: Vec<PlannedVersion>,
+    pub usage: Vec<Usage>,
+}
+
+/// A version a dry run would publish.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedVersion {
+    pub repository: String,
+    // `name~version` as pushed to Soldeer
+    pub dependency: String,
+    pub url: String,
+    // the directory that would be pushed, only written when the dry run downloads versions
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct CrawlFailure {
+    pub repository: String,
+    // missing when the whole repository failed, e.g. while listing its versions
+    pub version: Option<String>,
+    pub cause: String,
+}
+
+impl fmt::Display for CrawlFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {}: {}", self.repository, version, self.cause),
+            None => write!(f, "{}: {}", self.repository, self.cause),
+        }
+    }
+}
+
+impl CrawlReport {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    pub fn print_summary(&self) {
+        if !self.planned.is_empty() {
+            println!("Would publish {} versions", self.planned.len());
+            for planned in self.planned.iter() {
+                println!(
+                    "  {} from {} at {}",
+                    planned.dependency,
+                    planned.url,
+                    planned.path.display()
+                );
+            }
+        }
+        println!(
+            "Crawled {} repositories, published {} versions, {} failures",
+            self.repositories,
+            self.published.len(),
+            self.failures.len()
+        );
+        for usage in self.usage.iter() {
+            let remaining = match usage.remaining {
+                Some(remaining) => format!("{} remaining", remaining),
+                None => "quota unknown".to_string(),
+            };
+            println!(
+                "  {}: {} requests, {}",
+                usage.credential, usage.requests, remaining
+            );
+        }
+        for failure in self.failures.iter() {
+            eprintln!("  failed {}", failure);
+        }
+    }
+
+    // adds the outcome of a single job to the whole crawl
+    pub fn merge(&mut self, other: CrawlReport) {
+        self.repositories += other.repositories;
+        self.published.extend(other.published);
+        self.failures.extend(other.failures);
+        self.planned.extend(other.planned);
+        self.usage.extend(other.usage);
+    }
+
+    pub fn fail(&mut self, repository: &str, version: Option<&str>, cause: String) {
+        let failure = CrawlFailure {
+            repository: repository.to_string(),
+            version: version.map(|version| version.to_string()),
+            cause,
+        };
+        eprintln!("Failed {}", failure);
+        self.failures.push(failure);
+    }
+}
+
+/// Everything a crawl shares between sources and repositories.
+pub struct Crawler<'a> {
+    pub store: &'a Store,
+    pub publisher: &'a dyn Publisher,
+    pub schedule: &'a Schedule,
+    pub shutdown: &'a Shutdown,
+    pub limits: &'a Limits,
+    // only report what would be published, without publishing or writing to the database
+    pub dry_run: bool,
+    // download and extract versions during a dry run
+    pub download: bool,
+}
+
+impl Crawler<'_> {
+    // Crawls the repositories of every source that are due, or all of them with `force`, running
+    // up to `concurrency` repositories at the same time
+    pub async fn crawl_due_repositories(
+        &self,
+        sources: &[(&dyn Source, &[RepositoryConfig])],
+        force: bool,
+        report: &mut CrawlReport,
+    ) {
+        let mut jobs: Vec<(&dyn Source, &RepositoryConfig)> = Vec::new();
+        for (source, repositories) in sources.iter() {
+            match self.due_repositories(*source, repositories, force, report) {
+                Ok(due) => jobs.extend(due.into_iter().map(|repository| (*source, repository))),
+                // the other sources still get crawled
+                Err(err) => report.fail(source.name(), None, err),
+            }
+        }
+
+        let mut crawls = stream::iter(jobs)
+            .map(|(source, repository_config)| async move {
+                let mut job_report = CrawlReport::default();
+                if !self.shutdown.is_requested() {
+                    self.crawl_repository(source, repository_config, &mut job_report)
+                        .await;
+                }
+                job_report
+            })
+            .buffer_unordered(self.limits.concurrency);
+        while let Some(job_report) = crawls.next().await {
+            report.merge(job_report);
+        }
+        for (source, _) in sources.iter() {
+            report.usage.extend(source.take_usage());
+        }
+    }
+
+    fn due_repositories<'r>(
+        &self,
+        source: &dyn Source,
+        repositories: &'r [RepositoryConfig],
+        force: bool,
+        report: &mut CrawlReport,
+    ) -> Result<Vec<&'r RepositoryConfig>, String> {
+        // Filter repositories that were checked more recently than their interval
+        let mut repository_intervals: Vec<(String, Duration, bool)> = Vec::new();
+        for repository in repositories.iter() {
+            match self.schedule.interval(repository) {
+                Ok((interval, fixed)) => {
+                    repository_intervals.push((repository.repo.clone(), interval, fixed))
+                }
+                Err(err) => report.fail(&repository.repo, None, err),
+            }
+        }
+        let due_repositories = self
+            .store
+            .get_due_repositories(repository_intervals)
+            .map_err(|err| format!("filtering repositories: {}", err))?;
+        println!(
+            "Found {} {} repositories that need updating",
+            due_repositories.len(),
+            source.name()
+        );
+        Ok(repositories
+            .iter()
+            .filter(|repository| force || due_repositories.contains(&repository.repo))
+            .collect())
+    }
+
+    // Crawls a single repository, recording every failure in `report` instead of aborting
+    pub async fn crawl_repository(
+        &self,
+        source: &dyn Source,
+        repository_config: &RepositoryConfig,
+        report: &mut CrawlReport,
+    ) {
+        report.repositories += 1;
+        let failures_before = report.failures.len();
+        let latest_release = self.crawl_versions(source, repository_config, report).await;
+        if self.shutdown.is_requested() {
+            // checked again on the next run, versions published so far are already recorded
+            println!("Interrupted {}", repository_config.repo);
+            return;
+        }
+
+        let repository = &repository_config.repo;
+        if self.dry_run {
+            return;
+        }
+        let state = match self.store.get_repository_state(repository) {
+            Ok(state) => state,
+            Err(err) => {
+                report.fail(repository, None, format!("reading crawl state: {}", err));
+                return;
+            }
+        };
+        let recorded = match report.failures[failures_before..].last() {
+            Some(failure) => {
+                let next_check_at = self.schedule.next_check(
+                    repository_config,
+                    state.last_release_at,
+                    state.consecutive_failures + 1,
+                );
+                println!("Next check of {} at {}", repository, next_check_at);
+                self.store
+                    .record_check_failure(repository, &failure.to_string(), next_check_at)
+            }
+            None => {
+                let last_release_at = latest_release.max(state.last_release_at);
+                let next_check_at = self
+                    .schedule
+                    .next_check(repository_config, last_release_at, 0);
+                println!("Next check of {} at {}", repository, next_check_at);
+                self.store
+                    .record_check_success(repository, last_release_at, next_check_at)
+            }
+        };
+        if let Err(err) = recorded {
+            report.fail(repository, None, format!("recording crawl state: {}", err));
+        }
+    }
+
+    // Publishes the new versions of a repository and returns when it last released one, if known
+    async fn crawl_versions(
+        &self,
+        source: &dyn Source,
+        repository_config: &RepositoryConfig,
+        report: &mut CrawlReport,
+    ) -> Option<DateTime<Utc>> {
+        let repository = repository_config.repo.clone();
+        let existing_versions: Vec<String> = match self.store.get_versions_for_repo(&repository) {
+            Ok(versions) => versions,
+            Err(err) => {
+                report.fail(&repository, None, format!("reading versions: {}", err));
+                return None;
+            }
+        };
+        let invalid_versions: Vec<String> =
+            match self.store.get_invalid_versions_for_repo(&repository) {
+                Ok(versions) => versions,
+                Err(err) => {
+                    report.fail(
+                        &repository,
+                        None,
+                        format!("reading invalid versions: {}", err),
+                    );
+                    return None;
+                }
+            };
+        let filter = match VersionFilter::new(repository_config) {
+            Ok(filter) => filter,
+            Err(err) => {
+                report.fail(&repository, None, err);
+                return None;
+            }
+        };
+        let failures_before = report.failures.len();
+        let fingerprint = repository_config.fingerprint();
+        let mut validators = match self.listing_validators(&repository, &fingerprint) {
+            Ok(validators) => validators,
+            Err(err) => {
+                report.fail(&repository, None, err);
+                return None;
+            }
+        };
+        let versions: Vec<VersionStruct> = match source
+            .retrieve_versions(repository_config, self.limits, &mut validators)
+            .await
+        {
+            Ok(Listing::Changed(versions)) => versions,
+            Ok(Listing::Unchanged) => {
+                println!("{} is unchanged since the last crawl", repository);
+                return None;
+            }
+            Err(err) => {
+                report.fail(&repository, None, format!("listing versions: {}", err));
+                return None;
+            }
+        };
+        let mut latest_release = versions
+            .iter()
+            .filter_map(|version| version.published_at)
+            .max();
+        // versions without a release date (e.g. tags) were released around the time they show up,
+        // which only says something once the repository has been crawled before
+        let has_history = !existing_versions.is_empty();
+        let versions = filter.apply(versions);
+
+        let dependency_name = source.dependency_name(repository_config);
+
+        for version in versions.into_iter() {
+            if self.shutdown.is_requested() {
+                break;
+            }
+            if existing_versions.contains(&version.name) || invalid_versions.contains(&version.name)
+            {
+                continue;
+            }
+            if self.dry_run {
+                self.plan_version(source, repository_config, &version, report)
+                    .await;
+                continue;
+            }
+            let download = self.limits.acquire(&host_of(&version.url)).await;
+            let materialized = source
+                .materialize(repository_config, &version, self.limits)
+                .await;
+            drop(download);
+            let path = match materialized {
+                Ok(path) => path,
+                // cut short by the shutdown, the version is crawled again on the next run
+                Err(_) if self.shutdown.is_requested() => break,
+                Err(err) => {
+                    let reason = match err {
+                        MaterializeError::Download(_) => InvalidReason::Download,
+                        MaterializeError::Unzip(_) => InvalidReason::Unzip,
+                    };
+                    record_invalid_version(
+                        self.store,
+                        &repository,
+                        &version,
+                        reason,
+                        &err.to_string(),
+                    );
+                    report.fail(&repository, Some(&version.name), err.to_string());
+                    continue;
+                }
+            };
+            let host = self.publisher.host();
+            let push = self.limits.acquire(&host).await;
+            if self.limits.throttle(&host).await.is_err() {
+                source.cleanup(&path);
+                break;
+            }
+            let published = self
+                .publisher
+                .publish(&dependency_name, &version.name, path.clone())
+                .await;
+            drop(push);
+            source.cleanup(&path);
+            if let Err(err) = published {
+                let reason = match err.kind {
+                    // already on the registry, only our records were missing it
+                    PushErrorKind::AlreadyExists => {
+                        record_version(self.store, &repository, &version, report);
+                        continue;
+                    }
+                    PushErrorKind::NameRejected => InvalidReason::NameRejected,
+                    PushErrorKind::Rejected => InvalidReason::PushRejected,
+                };
+                record_invalid_version(self.store, &repository, &version, reason, &err.cause);
+                report.fail(&repository, Some(&version.name), err.cause);
+                continue;
+            }
+            record_version(self.store, &repository, &version, report);
+            report
+                .published
+                .push(format!("{}~{}", dependency_name, version.name));
+            if version.published_at.is_none() && has_history {
+                latest_release = Some(Utc::now());
+            }
+        }
+
+        // the next crawl may only skip an unchanged listing once every version in it is handled
+        let handled = report.failures.len() == failures_before && !self.shutdown.is_requested();
+        if handled && !self.dry_run {
+            if let Err(err) = self
+                .store
+                .save_validators(&repository, &fingerprint, &validators)
+            {
+                report.fail(&repository, None, format!("recording validators: {}", err));
+            }
+        }
+        latest_release
+    }
+
+    // The validators of the last listing of `repository`, none while one of its versions is
+    // waiting for a retry since an unchanged listing would skip it
+    fn listing_validators(
+        &self,
+        repository: &str,
+        fingerprint: &str,
+    ) -> Result<Validators, String> {
+        let invalid_versions = self
+            .store
+            .list_invalid_versions(repository)
+            .map_err(|err| format!("reading invalid versions: {}", err))?;
+        if invalid_versions
+            .iter()
+            .any(|record| record.next_attempt_at.is_some())
+        {
+            return Ok(Validators::new());
+        }
+        self.store
+            .get_validators(repository, fingerprint)
+            .map_err(|err| format!("reading validators: {}", err))
+    }
+
+    async fn plan_version(
+        &self,
+        source: &dyn Source,
+        repository_config: &RepositoryConfig,
+        version: &VersionStruct,
+        report: &mut CrawlReport,
+    ) {
+        let path = if self.download {
+            // left on disk so that the content can be reviewed
+            let _download = self.limits.acquire(&host_of(&version.url)).await;
+            match source
+                .materialize(repository_config, version, self.limits)
+                .await
+            {
+                Ok(path) => path,
+                Err(err) => {
+                    report.fail(
+                        &repository_config.repo,
+                        Some(&version.name),
+                        err.to_string(),
+                    );
+                    return;
+                }
+            }
+        } else {
+            source.materialize_path(repository_config, version)
+        };
+        let dependency = format!(
+            "{}~{}",
+            source.dependency_name(repository_config),
+            version.name
+        );
+        println!("Would publish {} from {}", dependency, version.url);
+        report.planned.push(PlannedVersion {
+            repository: repository_config.repo.clone(),
+            dependency,
+            url: version.url.clone(),
+            path,
+        });
+    }
+}
+
+fn record_version(
+    store: &Store,
+    repository: &str,
+    version: &VersionStruct,
+    report: &mut CrawlReport,
+) {
+    let version_to_insert: Version = Version {
+        repository: repository.to_string(),
+        version: version.name.clone(),
+        tag: version.tag.clone(),
+        last_updated: Utc::now(),
+    };
+    if let Err(err) = store.insert_version(version_to_insert) {
+        report.fail(
+            repository,
+            Some(&version.name),
+            format!("recording version: {}", err),
+        );
+    }
+}
+
+fn record_invalid_version(
+    store: &Store,
+    repository: &str,
+    version: &VersionStruct,
+    reason: InvalidReason,
+    error: &str,
+) {
+    let version_to_insert: InvalidVersion = InvalidVersion {
+        repository: repository.to_string(),
+        version: version.name.clone(),
+        tag: version.tag.clone(),
+        reason,
+        error: error.to_string(),
+        last_updated: Utc::now(),
+    };
+    if let Err(err) = store.insert_invalid_version(version_to_insert) {
+        eprintln!("Error inserting invalid version into db: {}", err);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::config::RepositoryConfig;
+use crate::crawl::{CrawlReport, Crawler};
+use crate::db::Store;
+use crate::limits::Limits;
+use crate::manager::Publisher;
+use crate::schedule::Schedule;
+use crate::shutdown::Shutdown;
+use crate::source::Source;
+use crate::utils::get_current_working_dir;
+use chrono::{Duration, Utc};
+use std::fs;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// the longest the daemon sleeps, so that changes to repositories.toml are picked up quickly
+const POLL_INTERVAL_SECONDS: u64 = 60;
+
+/// What the daemon crawls, reloaded whenever repositories.toml changes.
+struct Configuration {
+    schedule: Schedule,
+    limits: Limits,
+    repositories: Vec<Vec<RepositoryConfig>>,
+    modified: Option<SystemTime>,
+}
+
+// Crawls `sources` whenever one of their repositories is due until a shutdown is requested
+pub async fn run_daemon(
+    store: &Store,
+    sources: &[Box<dyn Source>],
+    publisher: &dyn Publisher,
+    interval: Option<String>,
+    concurrency: Option<usize>,
+    shutdown: &Arc<Shutdown>,
+) -> Result<(), String> {
+    let mut configuration = load_configuration(sources, interval.clone(), concurrency, shutdown)?;
+    println!("Daemon started, crawling {} sources", sources.len());
+
+    while !shutdown.is_requested() {
+        if repositories_modified() != configuration.modified {
+            match load_configuration(sources, interval.clone(), concurrency, shutdown) {
+                Ok(reloaded) => {
+                    println!("Reloaded repositories.toml");
+                    // hosts paused by a rate limit stay paused
+                    configuration.limits.update(reloaded.limits);
+                    configuration.schedule = reloaded.schedule;
+                    configuration.repositories = reloaded.repositories;
+                    configuration.modified = reloaded.modified;
+                }
+                // keep crawling with the previous configuration until the file is fixed
+                Err(err) => {
+                    eprintln!("Error reloading repositories.toml: {}", err);
+                    configuration.modified = repositories_modified();
+                }
+            }
+        }
+
+        let crawler = Crawler {
+            store,
+            publisher,
+            schedule: &configuration.schedule,
+            shutdown,
+            limits: &configuration.limits,
+            dry_run: false,
+            download: false,
+        };
+        let mut report = CrawlReport::default();
+        let crawled: Vec<(&dyn Source, &[RepositoryConfig])> = sources
+            .iter()
+            .zip(configuration.repositories.iter())
+            .map(|(source, repositories)| (source.as_ref(), repositories.as_slice()))
+            .collect();
+        crawler
+            .crawl_due_repositories(&crawled, false, &mut report)
+            .await;
+        if report.repositories > 0 {
+            report.print_summary();
+        }
+
+        let mut repository_intervals: Vec<(String, Duration, bool)> = Vec::new();
+        for repository in configuration.repositories.iter().flatten() {
+            if let Ok((interval, fixed)) = configuration.schedule.interval(repository) {
+                repository_intervals.push((repository.repo.clone(), interval, fixed));
+            }
+        }
+        let poll_interval = std::time::Duration::from_secs(POLL_INTERVAL_SECONDS);
+        let wait = match store.get_next_check_at(repository_intervals) {
+            Ok(Some(next_check_at)) => {
+                if report.repositories > 0 {
+                    println!("Next repository due at {}", next_check_at);
+                }
+                (next_check_at - Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .min(poll_interval)
+            }
+            Ok(None) => poll_interval,
+            Err(err) => {
+                eprintln!("Error reading the schedule: {}", err);
+                poll_interval
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown.wait() => {}
+        }
+    }
+
+    println!("Daemon stopped");
+    Ok(())
+}
+
+fn load_configuration(
+    sources: &[Box<dyn Source>],
+    interval: Option<String>,
+    concurrency: Option<usize>,
+    shutdown: &Arc<Shutdown>,
+) -> Result<Configuration, String> {
+    let modified = repositories_modified();
+    let schedule = Schedule::load(interval)?;
+    let limits = Limits::load(concurrency, shutdown.clone())?;
+    let mut repositories = Vec::new();
+    for source in sources {
+        let source_repositories = source
+            .load_repositories()
+            .map_err(|err| format!("loading {} repositories: {}", source.name(), err))?;
+        repositories.push(source_repositories);
+    }
+    Ok(Configuration {
+        schedule,
+        limits,
+        repositories,
+        modified,
+    })
+}
+
+fn repositories_modified() -> Option<SystemTime> {
+    let path = get_current_working_dir().ok()?.join("repositories.toml");
+    fs::metadata(path).ok()?.modified().ok()
+}
@@ -1,208 +1,926 @@
+use crate::source::{Validator, Validators};
+use crate::utils::format_time;
 use chrono::{DateTime, Duration, Utc};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use rusqlite::OptionalExtension;
 use rusqlite::{Connection, Error, Result};
 use serde_derive::{Deserialize, Serialize};
-use std::fmt::{self};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
 
 #[derive(Deserialize, Serialize)]
 pub struct Version {
     pub repository: String,
     pub version: String,
+    // the git tag the version was parsed from, none for npm packages
+    pub tag: Option<String>,
     pub last_updated: DateTime<Utc>,
 }
 
-pub fn get_versions_for_repo_from_db(repository: String) -> Result<Vec<String>, Error> {
-    let conn = Connection::open("repositories.db")?;
+// Transient failures are retried this many times, waiting twice as long after every attempt
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY_MINUTES: i64 = 60;
 
-    conn.execute(
-        "create table if not exists versions (
-             id integer primary key,
-             repository text not null,
-             version text not null,
-             last_updated datetime not null
-         )",
-        (),
-    )?;
-    let mut stmt: rusqlite::Statement<'_> =
-        conn.prepare("SELECT version from versions where repository = ?1")?;
-
-    let versions = stmt.query_map([&repository], |row| Ok(row.get(0)?))?;
+/// A version that failed to be crawled and why.
+pub struct InvalidVersion {
+    pub repository: String,
+    pub version: String,
+    pub tag: Option<String>,
+    pub reason: InvalidReason,
+    pub error: String,
+    pub last_updated: DateTime<Utc>,
+}
 
-    Ok(versions
-        .map(|version: std::result::Result<String, Error>| version.unwrap())
-        .collect())
+/// An invalid version as stored, with where its retries stand.
+pub struct InvalidVersionRecord {
+    pub invalid_version: InvalidVersion,
+    pub attempts: u32,
+    // missing once the version is quarantined
+    pub next_attempt_at: Option<DateTime<Utc>>,
 }
 
-pub fn get_invalid_versions_for_repo_from_db(repository: String) -> Result<Vec<String>, Error> {
-    let conn = Connection::open("repositories.db")?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidReason {
+    Download,
+    // failed npm installs, only found in rows recorded before tarballs were verified
+    Install,
+    Unzip,
+    PushRejected,
+    NameRejected,
+    // marked invalid by hand with `crawler mark-invalid`
+    Manual,
+}
 
-    conn.execute(
-        "create table if not exists invalid_versions (
-             id integer primary key,
-             repository text not null,
-             version text not null,
-             last_updated datetime not null
-         )",
-        (),
-    )?;
-    let mut stmt: rusqlite::Statement<'_> =
-        conn.prepare("SELECT version from invalid_versions where repository = ?1")?;
+impl InvalidReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InvalidReason::Download => "download",
+            InvalidReason::Install => "install",
+            InvalidReason::Unzip => "unzip",
+            InvalidReason::PushRejected => "push-rejected",
+            InvalidReason::NameRejected => "name-rejected",
+            InvalidReason::Manual => "manual",
+        }
+    }
 
-    let versions = stmt.query_map([&repository], |row| Ok(row.get(0)?))?;
+    // failures that can go away on their own, e.g. a registry blip, as opposed to a broken package
+    pub fn is_transient(&self) -> bool {
+        matches!(self, InvalidReason::Download | InvalidReason::PushRejected)
+    }
+}
 
-    Ok(versions
-        .map(|version: std::result::Result<String, Error>| version.unwrap())
-        .collect())
+impl FromSql for InvalidReason {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "download" => Ok(InvalidReason::Download),
+            "install" => Ok(InvalidReason::Install),
+            "unzip" => Ok(InvalidReason::Unzip),
+            "push-rejected" => Ok(InvalidReason::PushRejected),
+            "name-rejected" => Ok(InvalidReason::NameRejected),
+            "manual" => Ok(InvalidReason::Manual),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
 }
 
-pub fn insert_version_into_db(version: Version) -> Result<(), Error> {
-    println!(
-        "Inserting version {:?} into db for {:?}",
-        version.version, version.repository
-    );
-    let conn = Connection::open("repositories.db")?;
+// Ordered schema migrations, each one runs exactly once and is recorded in `schema_version`.
+// Never edit a migration that has shipped, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: the tables as they were created before migrations existed
+    "create table if not exists versions (
+         id integer primary key,
+         repository text not null,
+         version text not null,
+         last_updated datetime not null
+     );
+     create table if not exists invalid_versions (
+         id integer primary key,
+         repository text not null,
+         version text not null,
+         last_updated datetime not null
+     );",
+    // 2: one row per (repository, version), keeping the most recent duplicate
+    "create table versions_new (
+         id integer primary key,
+         repository text not null,
+         version text not null,
+         last_updated datetime not null,
+         unique(repository, version)
+     );
+     insert into versions_new (repository, version, last_updated)
+         select repository, version, max(last_updated) from versions
+         group by repository, version order by min(id);
+     drop table versions;
+     alter table versions_new rename to versions;
+     create index versions_repository on versions (repository);
 
-    conn.execute(
-        "create table if not exists versions (
-             id integer primary key,
-             repository text not null unique,
-             version text not null unique,
-             last_updated datetime not null
-         )",
-        (),
-    )?;
+     create table invalid_versions_new (
+         id integer primary key,
+         repository text not null,
+         version text not null,
+         last_updated datetime not null,
+         unique(repository, version)
+     );
+     insert into invalid_versions_new (repository, version, last_updated)
+         select repository, version, max(last_updated) from invalid_versions
+         group by repository, version order by min(id);
+     drop table invalid_versions;
+     alter table invalid_versions_new rename to invalid_versions;
+     create index invalid_versions_repository on invalid_versions (repository);",
+    // 3: crawl bookkeeping per repository, replacing the empty-version rows that only existed
+    // to bump `last_updated`
+    "create table repository_state (
+         repository text primary key,
+         last_checked_at datetime,
+         last_success_at datetime,
+         last_error text,
+         consecutive_failures integer not null default 0
+     );
+     insert into repository_state (repository, last_checked_at, last_success_at)
+         select repository, max(last_updated), max(last_updated) from (
+             select repository, last_updated from versions
+             union all
+             select repository, last_updated from invalid_versions
+         ) group by repository;
+     delete from versions where version = '';",
+    // 4: timestamps written by `DateTime::to_string` (`2024-01-01 00:00:00.123456 UTC`) rewritten
+    // in the RFC 3339 format used by rusqlite (`2024-01-01 00:00:00.123456+00:00`)
+    "update versions set last_updated = replace(last_updated, ' UTC', '+00:00')
+         where last_updated like '% UTC';
+     update invalid_versions set last_updated = replace(last_updated, ' UTC', '+00:00')
+         where last_updated like '% UTC';
+     update repository_state set
+         last_checked_at = replace(last_checked_at, ' UTC', '+00:00'),
+         last_success_at = replace(last_success_at, ' UTC', '+00:00');
+     update schema_version set applied_at = replace(applied_at, ' UTC', '+00:00')
+         where applied_at like '% UTC';",
+    // 5: why a version failed and when to retry it, rows without `next_attempt_at` are quarantined.
+    // Every failure recorded so far was a failed npm install.
+    "alter table invalid_versions add column reason text not null default 'install';
+     alter table invalid_versions add column error text;
+     alter table invalid_versions add column attempts integer not null default 1;
+     alter table invalid_versions add column next_attempt_at datetime;",
+    // 6: when the repository last released a version and when it is due again
+    "alter table repository_state add column last_release_at datetime;
+     alter table repository_state add column next_check_at datetime;",
+    // 7: ETag and Last-Modified of every listing fetched for a repository. A GitHub repository is
+    // listed over several pages and strategies, so they are kept per listing rather than per
+    // repository.
+    // `config` fingerprints the settings of the repository the listings were crawled with.
+    "create table listing_validators (
+         repository text not null,
+         url text not null,
+         etag text,
+         last_modified text,
+         config text not null,
+         primary key (repository, url)
+     );",
+    // 8: the git tag every version was parsed from, unknown for the versions recorded before
+    "alter table versions add column tag text;
+     alter table invalid_versions add column tag text;",
+];
 
-    let mut stmt: rusqlite::Statement<'_> = conn
-        .prepare("INSERT INTO versions (repository, version, last_updated) VALUES (?1, ?2, ?3)")?;
+const STATE_COLUMNS: &str = "last_checked_at, last_success_at, last_error, consecutive_failures,
+    last_release_at, next_check_at";
 
-    stmt.execute([
-        &version.repository,
-        &version.version,
-        &version.last_updated.to_string(),
-    ])?;
+/// What the crawler knows about the last checks of a repository.
+#[derive(Debug, Clone, Default)]
+pub struct RepositoryState {
+    pub last_checked_at: Option<DateTime<Utc>>,
+    pub last_success_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    // newest release seen so far, from release dates or from versions showing up between crawls
+    pub last_release_at: Option<DateTime<Utc>>,
+    pub next_check_at: Option<DateTime<Utc>>,
+}
 
-    Ok(())
+impl RepositoryState {
+    fn from_row(row: &rusqlite::Row) -> Result<Self, Error> {
+        Ok(RepositoryState {
+            last_checked_at: row.get(0)?,
+            last_success_at: row.get(1)?,
+            last_error: row.get(2)?,
+            consecutive_failures: row.get(3)?,
+            last_release_at: row.get(4)?,
+            next_check_at: row.get(5)?,
+        })
+    }
 }
 
-pub fn insert_invalid_version_into_db(version: Version) -> Result<(), Error> {
-    println!(
-        "Inserting invalid_versions {:?} into db for {:?}",
-        version.version, version.repository
-    );
-    let conn = Connection::open("repositories.db")?;
+/// The crawler database, a single connection shared by the whole crawl.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
 
-    conn.execute(
-        "create table if not exists invalid_versions (
-             id integer primary key,
-             repository text not null unique,
-             version text not null unique,
-             last_updated datetime not null
-         )",
-        (),
-    )?;
+impl Store {
+    // Opens (or creates) the database at `path` and brings its schema up to date
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        println!("Opening database {}", path.display());
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        migrate(&mut conn)?;
+        Ok(Store {
+            conn: Mutex::new(conn),
+        })
+    }
 
-    let mut stmt: rusqlite::Statement<'_> = conn.prepare(
-        "INSERT INTO invalid_versions (repository, version, last_updated) VALUES (?1, ?2, ?3)",
-    )?;
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
 
-    stmt.execute([
-        &version.repository,
-        &version.version,
-        &version.last_updated.to_string(),
-    ])?;
+    pub fn get_versions_for_repo(&self, repository: &str) -> Result<Vec<String>, Error> {
+        let conn = self.conn();
 
-    Ok(())
-}
+        let mut stmt: rusqlite::Statement<'_> =
+            conn.prepare("SELECT version from versions where repository = ?1")?;
 
-#[derive(Debug, Clone)]
-pub struct NotFound;
+        let versions = stmt.query_map([repository], |row| row.get(0))?;
 
-impl fmt::Display for NotFound {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "file not found")
+        versions.collect()
     }
-}
 
-pub fn get_repositories_not_updated_in_last_hour(
-    all_repositories: Vec<String>,
-) -> Result<Vec<String>, Error> {
-    let conn = Connection::open("repositories.db")?;
+    // the invalid versions to skip, i.e. quarantined ones and the ones waiting for their next attempt
+    pub fn get_invalid_versions_for_repo(&self, repository: &str) -> Result<Vec<String>, Error> {
+        let conn = self.conn();
 
-    // Ensure the tables exist
-    conn.execute(
-        "create table if not exists versions (
-             id integer primary key,
-             repository text not null,
-             version text not null,
-             last_updated datetime not null
-         )",
-        (),
-    )?;
+        let mut stmt: rusqlite::Statement<'_> = conn.prepare(
+            "SELECT version from invalid_versions where repository = ?1
+             AND (next_attempt_at IS NULL OR next_attempt_at > ?2)",
+        )?;
 
-    conn.execute(
-        "create table if not exists invalid_versions (
-             id integer primary key,
-             repository text not null,
-             version text not null,
-             last_updated datetime not null
-         )",
-        (),
-    )?;
+        let versions = stmt.query_map((repository, Utc::now()), |row| row.get(0))?;
+
+        versions.collect()
+    }
+
+    pub fn list_versions(&self, repository: &str) -> Result<Vec<Version>, Error> {
+        let conn = self.conn();
 
-    let one_hour_ago = Utc::now() - Duration::hours(1);
-    let mut repositories_to_update = Vec::new();
-
-    for repository in all_repositories {
-        // Get the most recent update time for this repository from both tables
-        let mut stmt = conn.prepare(
-            "SELECT MAX(last_updated) FROM (
-                SELECT last_updated FROM versions WHERE repository = ?1
-                UNION ALL
-                SELECT last_updated FROM invalid_versions WHERE repository = ?1
-            )",
+        let mut stmt: rusqlite::Statement<'_> = conn.prepare(
+            "SELECT version, last_updated, tag from versions where repository = ?1 ORDER BY id",
         )?;
 
-        let mut rows = stmt.query_map([&repository], |row| {
-            let last_updated_str: Option<String> = row.get(0)?;
-            Ok(last_updated_str)
+        let versions = stmt.query_map([repository], |row| {
+            Ok(Version {
+                repository: repository.to_string(),
+                version: row.get(0)?,
+                tag: row.get(2)?,
+                last_updated: row.get(1)?,
+            })
         })?;
 
-        let should_update = if let Some(Ok(Some(last_updated_str))) = rows.next() {
-            // Parse the datetime string and check if it's older than 1 hour
-            match last_updated_str
-                .strip_suffix(" UTC")
-                .and_then(|s| {
-                    chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S.%6f").ok()
-                })
-                .or_else(|| {
-                    last_updated_str.strip_suffix(" UTC").and_then(|s| {
-                        chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").ok()
-                    })
-                })
-                .or_else(|| {
-                    last_updated_str.strip_suffix(" UTC").and_then(|s| {
-                        chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").ok()
-                    })
+        versions.collect()
+    }
+
+    pub fn list_invalid_versions(
+        &self,
+        repository: &str,
+    ) -> Result<Vec<InvalidVersionRecord>, Error> {
+        let conn = self.conn();
+
+        let mut stmt: rusqlite::Statement<'_> = conn.prepare(
+            "SELECT version, last_updated, reason, error, attempts, next_attempt_at, tag
+             from invalid_versions where repository = ?1 ORDER BY id",
+        )?;
+
+        let versions = stmt.query_map([repository], |row| {
+            let error: Option<String> = row.get(3)?;
+            Ok(InvalidVersionRecord {
+                invalid_version: InvalidVersion {
+                    repository: repository.to_string(),
+                    version: row.get(0)?,
+                    tag: row.get(6)?,
+                    last_updated: row.get(1)?,
+                    reason: row.get(2)?,
+                    error: error.unwrap_or_default(),
+                },
+                attempts: row.get(4)?,
+                next_attempt_at: row.get(5)?,
+            })
+        })?;
+
+        versions.collect()
+    }
+
+    // Forgets that `version` is invalid and makes its repository due, returns false when the
+    // version wasn't invalid
+    pub fn retry_invalid_version(&self, repository: &str, version: &str) -> Result<bool, Error> {
+        let conn = self.conn();
+
+        let deleted = conn.execute(
+            "DELETE FROM invalid_versions WHERE repository = ?1 AND version = ?2",
+            (repository, version),
+        )?;
+        conn.execute(
+            "UPDATE repository_state SET next_check_at = ?2 WHERE repository = ?1",
+            (repository, Utc::now()),
+        )?;
+        // an unchanged listing would skip the version
+        conn.execute(
+            "DELETE FROM listing_validators WHERE repository = ?1",
+            [repository],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    pub fn insert_version(&self, version: Version) -> Result<(), Error> {
+        println!(
+            "Inserting version {:?} into db for {:?}",
+            version.version, version.repository
+        );
+        let conn = self.conn();
+
+        let mut stmt: rusqlite::Statement<'_> = conn.prepare(
+            "INSERT INTO versions (repository, version, last_updated, tag) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(repository, version) DO UPDATE SET
+                 last_updated = excluded.last_updated,
+                 tag = excluded.tag",
+        )?;
+
+        stmt.execute((
+            &version.repository,
+            &version.version,
+            version.last_updated,
+            &version.tag,
+        ))?;
+        // a retried version that finally went through is no longer invalid
+        conn.execute(
+            "DELETE FROM invalid_versions WHERE repository = ?1 AND version = ?2",
+            (&version.repository, &version.version),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn insert_invalid_version(&self, version: InvalidVersion) -> Result<(), Error> {
+        let conn = self.conn();
+
+        let previous_attempts: Option<u32> = conn
+            .query_row(
+                "SELECT attempts FROM invalid_versions WHERE repository = ?1 AND version = ?2",
+                (&version.repository, &version.version),
+                |row| row.get(0),
+            )
+            .optional()?;
+        let attempts = previous_attempts.unwrap_or(0) + 1;
+        let next_attempt_at = if version.reason.is_transient() && attempts < MAX_ATTEMPTS {
+            let delay = Duration::minutes(RETRY_BASE_DELAY_MINUTES << (attempts - 1));
+            println!(
+                "Inserting invalid_versions {:?} into db for {:?}, {} failure, retrying in {} minutes",
+                version.version,
+                version.repository,
+                version.reason.as_str(),
+                delay.num_minutes()
+            );
+            Some(version.last_updated + delay)
+        } else {
+            println!(
+                "Inserting invalid_versions {:?} into db for {:?}, {} failure, quarantined after {} attempts",
+                version.version,
+                version.repository,
+                version.reason.as_str(),
+                attempts
+            );
+            None
+        };
+
+        let mut stmt: rusqlite::Statement<'_> = conn.prepare(
+            "INSERT INTO invalid_versions
+                 (repository, version, last_updated, reason, error, attempts, next_attempt_at, tag)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT(repository, version) DO UPDATE SET
+                 last_updated = excluded.last_updated,
+                 tag = excluded.tag,
+                 reason = excluded.reason,
+                 error = excluded.error,
+                 attempts = excluded.attempts,
+                 next_attempt_at = excluded.next_attempt_at",
+        )?;
+
+        stmt.execute((
+            &version.repository,
+            &version.version,
+            version.last_updated,
+            version.reason.as_str(),
+            &version.error,
+            attempts,
+            next_attempt_at,
+            &version.tag,
+        ))?;
+
+        Ok(())
+    }
+
+    pub fn get_repository_state(&self, repository: &str) -> Result<RepositoryState, Error> {
+        let conn = self.conn();
+
+        let state = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM repository_state WHERE repository = ?1",
+                    STATE_COLUMNS
+                ),
+                [repository],
+                RepositoryState::from_row,
+            )
+            .optional()?;
+
+        // repositories never checked have no state yet
+        Ok(state.unwrap_or_default())
+    }
+
+    pub fn record_check_success(
+        &self,
+        repository: &str,
+        last_release_at: Option<DateTime<Utc>>,
+        next_check_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let conn = self.conn();
+        let now = Utc::now();
+
+        conn.execute(
+            "INSERT INTO repository_state
+                 (repository, last_checked_at, last_success_at, last_release_at, next_check_at)
+             VALUES (?1, ?2, ?2, ?3, ?4)
+             ON CONFLICT(repository) DO UPDATE SET
+                 last_checked_at = excluded.last_checked_at,
+                 last_success_at = excluded.last_success_at,
+                 last_error = NULL,
+                 consecutive_failures = 0,
+                 last_release_at = excluded.last_release_at,
+                 next_check_at = excluded.next_check_at",
+            (repository, now, last_release_at, next_check_at),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn record_check_failure(
+        &self,
+        repository: &str,
+        error: &str,
+        next_check_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let conn = self.conn();
+        let now = Utc::now();
+
+        conn.execute(
+            "INSERT INTO repository_state
+                 (repository, last_checked_at, last_error, consecutive_failures, next_check_at)
+             VALUES (?1, ?2, ?3, 1, ?4)
+             ON CONFLICT(repository) DO UPDATE SET
+                 last_checked_at = excluded.last_checked_at,
+                 last_error = excluded.last_error,
+                 consecutive_failures = consecutive_failures + 1,
+                 next_check_at = excluded.next_check_at",
+            (repository, now, error, next_check_at),
+        )?;
+
+        Ok(())
+    }
+
+    // The validators stored for the listings of `repository`, when crawled with the same `config`
+    pub fn get_validators(&self, repository: &str, config: &str) -> Result<Validators, Error> {
+        let conn = self.conn();
+
+        let mut stmt: rusqlite::Statement<'_> = conn.prepare(
+            "SELECT url, etag, last_modified FROM listing_validators
+             WHERE repository = ?1 AND config = ?2",
+        )?;
+        let validators = stmt
+            .query_map((repository, config), |row| {
+                Ok((
+                    row.get(0)?,
+                    Validator {
+                        etag: row.get(1)?,
+                        last_modified: row.get(2)?,
+                    },
+                ))
+            })?
+            .collect::<Result<Validators, Error>>()?;
+
+        Ok(validators)
+    }
+
+    // Replaces the validators of `repository` with the ones of its latest listing
+    pub fn save_validators(
+        &self,
+        repository: &str,
+        config: &str,
+        validators: &Validators,
+    ) -> Result<(), Error> {
+        let mut conn = self.conn();
+
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM listing_validators WHERE repository = ?1",
+            [repository],
+        )?;
+        for (url, validator) in validators.iter() {
+            tx.execute(
+                "INSERT INTO listing_validators (repository, url, etag, last_modified, config)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                (
+                    repository,
+                    url,
+                    &validator.etag,
+                    &validator.last_modified,
+                    config,
+                ),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    // When the first of `repositories` is due, now if one of them was never checked. Like
+    // `get_due_repositories`, a fixed interval caps how far away the stored next check can be.
+    pub fn get_next_check_at(
+        &self,
+        repositories: Vec<(String, Duration, bool)>,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        let mut next_check_at: Option<DateTime<Utc>> = None;
+        for (repository, interval, fixed) in repositories {
+            let state = self.get_repository_state(&repository)?;
+            let due_at = match (state.next_check_at, state.last_checked_at) {
+                (Some(next_check_at), Some(last_checked_at)) if fixed => {
+                    next_check_at.min(last_checked_at + interval)
+                }
+                (Some(next_check_at), _) => next_check_at,
+                (None, Some(last_checked_at)) => last_checked_at + interval,
+                (None, None) => Utc::now(),
+            };
+            next_check_at = Some(next_check_at.map_or(due_at, |next| next.min(due_at)));
+        }
+        Ok(next_check_at)
+    }
+
+    // The repositories due for a check: the ones whose `next_check_at` has passed, or for
+    // repositories scheduled before it existed, whose last check is older than their interval.
+    // A fixed interval, e.g. just lowered in repositories.toml, also wins over a later
+    // `next_check_at` scheduled with the previous settings.
+    pub fn get_due_repositories(
+        &self,
+        all_repositories: Vec<(String, Duration, bool)>,
+    ) -> Result<Vec<String>, Error> {
+        let conn = self.conn();
+
+        let now = Utc::now();
+        let mut repositories_to_update = Vec::new();
+        // timestamps share one format, so they compare correctly as text
+        let mut stmt: rusqlite::Statement<'_> = conn.prepare(&format!(
+            "SELECT {},
+                 CASE WHEN next_check_at IS NOT NULL
+                          THEN next_check_at <= ?3 OR (?4 AND last_checked_at < ?2)
+                      ELSE last_checked_at IS NULL OR last_checked_at < ?2 END
+             FROM repository_state WHERE repository = ?1",
+            STATE_COLUMNS
+        ))?;
+
+        for (repository, interval, fixed) in all_repositories {
+            let state = stmt
+                .query_row((&repository, now - interval, now, fixed), |row| {
+                    let due: bool = row.get(6)?;
+                    Ok((due, RepositoryState::from_row(row)?))
                 })
-                .map(|ndt| ndt.and_utc())
-            {
-                Some(last_updated) => last_updated < one_hour_ago,
-                None => {
-                    println!(
-                        "Failed to parse datetime for repository {}: {}",
-                        repository, last_updated_str
-                    );
-                    true // If we can't parse, assume it needs updating
+                .optional()?;
+
+            match state {
+                // repositories never checked need updating
+                None => repositories_to_update.push(repository),
+                Some((false, _)) => {}
+                Some((true, state)) => {
+                    if state.consecutive_failures > 0 {
+                        println!(
+                            "Retrying {} after {} failed checks (last checked {}, last success {}): {}",
+                            repository,
+                            state.consecutive_failures,
+                            format_time(state.last_checked_at),
+                            format_time(state.last_success_at),
+                            state.last_error.unwrap_or_default()
+                        );
+                    }
+                    repositories_to_update.push(repository);
                 }
             }
-        } else {
-            // No records found for this repository, so it needs updating
-            true
-        };
+        }
+
+        Ok(repositories_to_update)
+    }
+}
+
+// Brings the database up to the latest schema, upgrading existing files in place
+fn migrate(conn: &mut Connection) -> Result<(), Error> {
+    conn.execute(
+        "create table if not exists schema_version (
+             version integer not null,
+             applied_at datetime not null
+         )",
+        (),
+    )?;
+    let current: usize = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?;
 
-        if should_update {
-            repositories_to_update.push(repository);
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+        println!("Migrating database to schema version {}", index + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, applied_at) VALUES (?1, ?2)",
+            (index + 1, Utc::now()),
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Timelike;
+
+    // the tables as the crawler created them before migrations, with `DateTime::to_string` times
+    const LEGACY_SCHEMA: &str = "
+        create table versions (
+            id integer primary key,
+            repository text not null,
+            version text not null,
+            last_updated datetime not null
+        );
+        create table invalid_versions (
+            id integer primary key,
+            repository text not null,
+            version text not null,
+            last_updated datetime not null
+        );
+        insert into versions (repository, version, last_updated) values
+            ('Uniswap/v4-core', '1.0.0', '2023-01-01 00:00:00.123456 UTC'),
+            ('Uniswap/v4-core', '1.0.0', '2023-06-01 00:00:00.123456 UTC'),
+            ('Uniswap/v4-core', '', '2023-07-01 00:00:00.123456 UTC'),
+            ('@openzeppelin/contracts', '5.0.0', '2023-02-01 00:00:00 UTC'),
+            ('@openzeppelin/contracts', '', '2023-09-01 00:00:00 UTC');
+        insert into invalid_versions (repository, version, last_updated) values
+            ('Uniswap/v4-core', '0.9.0', '2023-08-01 00:00:00 UTC'),
+            ('solmate', '7', '2023-03-01 00:00:00 UTC'),
+            ('solmate', '7', '2023-04-01 00:00:00 UTC');";
+
+    fn time(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    fn store() -> Store {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        Store {
+            conn: Mutex::new(conn),
         }
     }
 
-    Ok(repositories_to_update)
+    fn set_state(
+        store: &Store,
+        repository: &str,
+        last_checked_at: DateTime<Utc>,
+        next_check_at: Option<DateTime<Utc>>,
+    ) {
+        store
+            .conn()
+            .execute(
+                "INSERT INTO repository_state (repository, last_checked_at, next_check_at)
+                 VALUES (?1, ?2, ?3)",
+                (repository, last_checked_at, next_check_at),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn migrates_legacy_databases() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(LEGACY_SCHEMA).unwrap();
+        migrate(&mut conn).unwrap();
+
+        let schema_version: usize = conn
+            .query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(schema_version, MIGRATIONS.len());
+
+        // duplicates keep their latest time, the empty-version rows are gone
+        let mut stmt = conn
+            .prepare("SELECT repository, version, last_updated FROM versions ORDER BY id")
+            .unwrap();
+        let versions: Vec<(String, String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(stmt);
+        assert_eq!(
+            versions,
+            vec![
+                (
+                    "Uniswap/v4-core".to_string(),
+                    "1.0.0".to_string(),
+                    "2023-06-01 00:00:00.123456+00:00".to_string()
+                ),
+                (
+                    "@openzeppelin/contracts".to_string(),
+                    "5.0.0".to_string(),
+                    "2023-02-01 00:00:00+00:00".to_string()
+                ),
+            ]
+        );
+        let duplicate = conn.execute(
+            "INSERT INTO versions (repository, version, last_updated) VALUES (?1, ?2, ?3)",
+            ("Uniswap/v4-core", "1.0.0", Utc::now()),
+        );
+        assert!(duplicate.is_err());
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT repository, version, last_updated, reason, attempts, next_attempt_at
+                 FROM invalid_versions ORDER BY id",
+            )
+            .unwrap();
+        let invalid_versions: Vec<(String, String, DateTime<Utc>, InvalidReason, u32)> = stmt
+            .query_map([], |row| {
+                let next_attempt_at: Option<DateTime<Utc>> = row.get(5)?;
+                assert_eq!(next_attempt_at, None);
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            invalid_versions,
+            vec![
+                (
+                    "Uniswap/v4-core".to_string(),
+                    "0.9.0".to_string(),
+                    time("2023-08-01T00:00:00Z"),
+                    InvalidReason::Install,
+                    1
+                ),
+                (
+                    "solmate".to_string(),
+                    "7".to_string(),
+                    time("2023-04-01T00:00:00Z"),
+                    InvalidReason::Install,
+                    1
+                ),
+            ]
+        );
+
+        drop(stmt);
+
+        let columns: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_table_info('repository_state')")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert!(!columns.contains(&"etag".to_string()));
+        assert!(!columns.contains(&"cursor".to_string()));
+
+        // every repository is seeded with the last time it was touched, empty-version rows included
+        let store = Store {
+            conn: Mutex::new(conn),
+        };
+        let state = store.get_repository_state("Uniswap/v4-core").unwrap();
+        assert_eq!(state.last_checked_at, Some(time("2023-08-01T00:00:00Z")));
+        assert_eq!(state.last_success_at, state.last_checked_at);
+        assert_eq!(state.consecutive_failures, 0);
+        let state = store
+            .get_repository_state("@openzeppelin/contracts")
+            .unwrap();
+        assert_eq!(state.last_checked_at, Some(time("2023-09-01T00:00:00Z")));
+        let state = store.get_repository_state("solmate").unwrap();
+        assert_eq!(state.last_checked_at, Some(time("2023-04-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(LEGACY_SCHEMA).unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+
+        let migrations: usize = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(migrations, MIGRATIONS.len());
+        let versions: usize = conn
+            .query_row("SELECT COUNT(*) FROM versions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(versions, 2);
+    }
+
+    #[test]
+    fn due_repositories_compare_times_as_text() {
+        let store = store();
+        let now = Utc::now();
+        let hour = Duration::hours(1);
+        set_state(&store, "recent", now - Duration::minutes(30), None);
+        set_state(&store, "stale", now - Duration::hours(2), None);
+        // written without a fraction, it sorts before the fractional cutoff of the same second
+        let whole_second = (now - hour).with_nanosecond(0).unwrap();
+        set_state(&store, "whole-second", whole_second, None);
+        // `next_check_at` wins over `last_checked_at`
+        set_state(
+            &store,
+            "scheduled-later",
+            now - Duration::hours(2),
+            Some(now + hour),
+        );
+        set_state(
+            &store,
+            "scheduled-earlier",
+            now - Duration::minutes(1),
+            Some(now - Duration::seconds(1)),
+        );
+
+        let repositories = [
+            "never-checked",
+            "recent",
+            "stale",
+            "whole-second",
+            "scheduled-later",
+            "scheduled-earlier",
+        ];
+        let due = store
+            .get_due_repositories(
+                repositories
+                    .iter()
+                    .map(|repository| (repository.to_string(), hour, false))
+                    .collect(),
+            )
+            .unwrap();
+        assert_eq!(
+            due,
+            vec![
+                "never-checked",
+                "stale",
+                "whole-second",
+                "scheduled-earlier"
+            ]
+        );
+    }
+
+    #[test]
+    fn fixed_intervals_cap_the_next_check() {
+        let store = store();
+        let now = Utc::now();
+        let hour = Duration::hours(1);
+        // scheduled a week out by the release cadence, then given an interval of an hour
+        set_state(
+            &store,
+            "fixed",
+            now - hour * 2,
+            Some(now + Duration::weeks(1)),
+        );
+        set_state(
+            &store,
+            "cadence",
+            now - hour * 2,
+            Some(now + Duration::weeks(1)),
+        );
+
+        let due = store
+            .get_due_repositories(vec![
+                ("fixed".to_string(), hour, true),
+                ("cadence".to_string(), hour, false),
+            ])
+            .unwrap();
+        assert_eq!(due, vec!["fixed"]);
+
+        let next_check_at = store
+            .get_next_check_at(vec![("fixed".to_string(), hour * 3, true)])
+            .unwrap()
+            .unwrap();
+        assert!((next_check_at - (now + hour)).num_seconds().abs() < 1);
+        let next_check_at = store
+            .get_next_check_at(vec![("cadence".to_string(), hour * 3, false)])
+            .unwrap()
+            .unwrap();
+        assert!(
+            (next_check_at - (now + Duration::weeks(1)))
+                .num_seconds()
+                .abs()
+                < 1
+        );
+    }
 }
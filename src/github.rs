@@ -1,13 +1,145 @@
-use crate::utils::{format_version, get_current_working_dir, read_file_to_string};
+use crate::config::{RepositoryConfig, RepositoryEntry, VersionStrategy};
+use crate::limits::{host_of, Limits, MAX_RATE_LIMITED_ATTEMPTS};
+use crate::source::{Listing, LoadError, MaterializeError, Source, Usage, Validator, Validators};
+use crate::tokens::TokenPool;
+use crate::utils::{
+    format_dependency_name, get_current_working_dir, get_job_dir, read_file_to_string,
+};
+use crate::version::TagParser;
 use crate::VersionStruct;
-use curl::easy::{Easy, List};
+use async_trait::async_trait;
+use http::header::{HeaderMap, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use http::StatusCode;
+use octocrab::models::repos::{Branch, Release, Tag};
+use octocrab::{FromResponse, Page};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::{BufReader, Cursor, Read};
+use std::io::{BufReader, Cursor, ErrorKind, Read};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-pub fn load_repositories() -> Result<Vec<String>, LoadError> {
+const GITHUB_API_HOST: &str = "api.github.com";
+// GitHub turns down requests without a user agent
+const USER_AGENT: &str = "soldeer-crawler";
+
+pub struct GithubSource {
+    // built on the first request, commands that don't talk to GitHub never need tokens
+    tokens: OnceLock<TokenPool>,
+    // downloads the zipballs
+    client: Client,
+}
+
+impl GithubSource {
+    pub fn new() -> Self {
+        GithubSource {
+            tokens: OnceLock::new(),
+            client: Client::builder()
+                .user_agent(USER_AGENT)
+                .build()
+                .expect("Failed to build HTTP client"),
+        }
+    }
+
+    fn tokens(&self) -> &TokenPool {
+        self.tokens.get_or_init(TokenPool::from_env)
+    }
+}
+
+#[async_trait]
+impl Source for GithubSource {
+    fn name(&self) -> &'static str {
+        "github"
+    }
+
+    fn load_repositories(&self) -> Result<Vec<RepositoryConfig>, LoadError> {
+        load_repositories()
+    }
+
+    async fn retrieve_versions(
+        &self,
+        repository: &RepositoryConfig,
+        limits: &Limits,
+        validators: &mut Validators,
+    ) -> Result<Listing, LoadError> {
+        github_retrieve_versions(self.tokens(), repository, limits, validators).await
+    }
+
+    fn materialize_path(&self, repository: &RepositoryConfig, version: &VersionStruct) -> PathBuf {
+        get_job_dir(
+            self.name(),
+            &self.dependency_name(repository),
+            &version.name,
+        )
+    }
+
+    async fn materialize(
+        &self,
+        repository: &RepositoryConfig,
+        version: &VersionStruct,
+        limits: &Limits,
+    ) -> Result<PathBuf, MaterializeError> {
+        let target = self.materialize_path(repository, version);
+        if target.exists() {
+            self.cleanup(&target);
+        }
+        let archive = archive_path(&target);
+        if let Err(err) =
+            download_dependency(&self.client, self.tokens(), limits, version, &archive).await
+        {
+            return Err(MaterializeError::Download(format!(
+                "{} {} {}",
+                repository.repo, version.name, err.cause
+            )));
+        }
+        if let Err(err) = unzip_dependency(&archive, &target).await {
+            self.cleanup(&target);
+            return Err(MaterializeError::Unzip(format!(
+                "{} {} {}",
+                repository.repo, version.name, err.cause
+            )));
+        }
+        Ok(target)
+    }
+
+    fn dependency_name(&self, repository: &RepositoryConfig) -> String {
+        format_dependency_name(repository)
+            .replace("/", "-")
+            .replace(".", "-")
+    }
+
+    fn take_usage(&self) -> Vec<Usage> {
+        match self.tokens.get() {
+            Some(tokens) => tokens.take_usage(),
+            None => Vec::new(),
+        }
+    }
+
+    fn cleanup(&self, path: &Path) {
+        for removed in [
+            fs::remove_dir_all(path),
+            fs::remove_file(archive_path(path)),
+        ] {
+            if let Err(err) = removed {
+                if err.kind() != ErrorKind::NotFound {
+                    eprintln!("Error removing {}: {}", path.display(), err);
+                }
+            }
+        }
+    }
+}
+
+// the zipball is downloaded next to the directory it is extracted to
+fn archive_path(target: &Path) -> PathBuf {
+    let mut archive = target.as_os_str().to_owned();
+    archive.push(".zip");
+    PathBuf::from(archive)
+}
+
+pub fn load_repositories() -> Result<Vec<RepositoryConfig>, LoadError> {
     println!("Loading list of repositories for Github");
     let filename: String = get_current_working_dir()
         .unwrap()
@@ -15,7 +147,9 @@ pub fn load_repositories() -> Result<Vec<String>, LoadError> {
         .to_str()
         .unwrap()
         .to_string();
-    let contents = read_file_to_string(filename.clone()).unwrap();
+    let contents = read_file_to_string(filename.clone()).map_err(|err| LoadError {
+        cause: format!("reading {}: {}", filename, err),
+    })?;
     let data: Data = match toml::from_str(&contents) {
         // If successful, return data as `Data` struct.
         // `d` is a local variable.
@@ -26,279 +160,557 @@ pub fn load_repositories() -> Result<Vec<String>, LoadError> {
             // Write `msg` to `stderr`.
             eprintln!("Unable to load data from repositories.toml");
             // Exit the program with exit code `1`.
-            return Err(LoadError);
+            return Err(LoadError {
+                cause: format!("parsing {}: {}", filename, err),
+            });
         }
     };
 
-    let mut repositories: Vec<String> = Vec::new();
-    data.github.iter().for_each(|value: &String| {
-        repositories.push(value.clone());
-    });
+    let repositories: Vec<RepositoryConfig> = data
+        .github
+        .into_iter()
+        .map(RepositoryConfig::from)
+        .collect();
+    // everything else assumes `owner/name`
+    for repository in repositories.iter() {
+        let valid = repository
+            .repo
+            .split_once("/")
+            .is_some_and(|(owner, name)| {
+                !owner.is_empty() && !name.is_empty() && !name.contains("/")
+            });
+        if !valid {
+            return Err(LoadError {
+                cause: format!(
+                    "invalid GitHub repository {:?}, expected owner/name",
+                    repository.repo
+                ),
+            });
+        }
+    }
 
     Ok(repositories)
 }
 
-pub async fn github_retrieve_versions(repository: &str) -> Result<Vec<VersionStruct>, LoadError> {
-    println!("repository: {}", repository);
+pub async fn github_retrieve_versions(
+    tokens: &TokenPool,
+    repository: &RepositoryConfig,
+    limits: &Limits,
+    validators: &mut Validators,
+) -> Result<Listing, LoadError> {
+    println!("repository: {}", repository.repo);
 
-    // Try to get GitHub token from environment variable and build octocrab instance
-    let octocrab_builder = match std::env::var("GITHUB_TOKEN") {
-        Ok(token) => Some(
-            octocrab::OctocrabBuilder::new()
-                .personal_token(token)
-                .build()
-                .expect("Failed to build Octocrab instance with token"),
-        ),
-        Err(_) => {
-            eprintln!("Warning: GITHUB_TOKEN not set. Using unauthenticated API access (lower rate limits)");
-            eprintln!("Set GITHUB_TOKEN environment variable for higher rate limits");
-            None
-        }
-    };
-
-    let octocrab = if let Some(instance) = octocrab_builder {
-        std::sync::Arc::new(instance)
-    } else {
-        octocrab::instance()
-    };
-
-    let split_versions: Vec<&str> = repository.split("/").collect();
-    let page = match octocrab
-        .repos(split_versions[0], split_versions[1])
-        .releases()
-        .list()
-        // Optional Parameters
-        .per_page(100)
-        .page(0u32)
-        // Send the request
-        .send()
-        .await
+    let mut prefetched = Prefetched::new();
+    if !validators.is_empty()
+        && listings_unchanged(tokens, limits, validators, &mut prefetched).await
     {
-        Ok(page) => page,
+        return Ok(Listing::Unchanged);
+    }
+    let mut fetched = Validators::new();
+
+    let parser = match TagParser::new(repository) {
+        Ok(parser) => parser,
         Err(err) => {
-            eprintln!("Error fetching releases for {}: {}", repository, err);
-            eprintln!(
-                "This might be due to GitHub API rate limiting. Please wait and try again later."
-            );
-            eprintln!("Consider setting up a GitHub token via GITHUB_TOKEN environment variable for higher rate limits.");
-            return Err(LoadError);
+            return Err(LoadError {
+                cause: format!("invalid tag_pattern: {}", err),
+            });
         }
     };
+
     let mut versions: Vec<VersionStruct> = Vec::new();
-    if repository != "morpho-org/morpho-blue"
-        && repository != "morpho-org/public-allocator"
-        && repository != "gnsps/solidity-bytes-utils"
-    {
-        for val in page.into_iter().rev() {
-            let mut unsplit_name = val.name.unwrap();
-            if unsplit_name.is_empty() {
-                unsplit_name = val.tag_name;
+    for strategy in repository.strategies() {
+        let found = match strategy {
+            VersionStrategy::Releases => {
+                retrieve_releases(
+                    tokens,
+                    limits,
+                    &repository.repo,
+                    &parser,
+                    repository.max_pages,
+                    &mut prefetched,
+                    &mut fetched,
+                )
+                .await?
             }
-            let mut name = unsplit_name.as_str();
-            if unsplit_name.contains("v") {
-                (_, name) = unsplit_name.split_once("v").unwrap();
-            } else if unsplit_name.contains(" ") {
-                let splitted: Vec<&str> = unsplit_name.split(" ").collect();
-                name = splitted[splitted.len() - 1];
+            VersionStrategy::Tags => {
+                retrieve_tags(
+                    tokens,
+                    limits,
+                    &repository.repo,
+                    &parser,
+                    repository.max_pages,
+                    &mut prefetched,
+                    &mut fetched,
+                )
+                .await?
             }
-            versions.push(VersionStruct {
-                name: name.to_string(),
-                url: val.zipball_url.unwrap().to_string(),
-            });
+            VersionStrategy::DefaultBranch => {
+                retrieve_branch_head(
+                    tokens,
+                    limits,
+                    &repository.repo,
+                    repository.branch.as_deref(),
+                    &mut prefetched,
+                    &mut fetched,
+                )
+                .await?
+            }
+        };
+        for version in found {
+            if !versions.iter().any(|v| v.name == version.name) {
+                versions.push(version);
+            }
+        }
+        // strategies are a fallback chain unless the repository asks for all of them
+        if !versions.is_empty() && !repository.merge_strategies {
+            break;
         }
     }
+    if repository.snapshot_branch {
+        let found = retrieve_branch_head(
+            tokens,
+            limits,
+            &repository.repo,
+            repository.branch.as_deref(),
+            &mut prefetched,
+            &mut fetched,
+        )
+        .await?;
+        for version in found {
+            if !versions.iter().any(|v| v.name == version.name) {
+                versions.push(version);
+            }
+        }
+    }
+    *validators = fetched;
+    Ok(Listing::Changed(versions))
+}
 
-    //tags
-    if (versions.is_empty() && repository != "Uniswap/permit2")
-        || repository == "morpho-org/morpho-blue"
-        || repository == "gnsps/solidity-bytes-utils"
-        || repository == "smartcontractkit/chainlink-evm"
-        || repository == "manifoldxyz/creator-core-solidity"
-        || repository == "Balmy-protocol/uniswap-v3-oracle"
-        || repository == "Recon-Fuzz/chimera"
-    {
-        let page = match octocrab
-            .repos(split_versions[0], split_versions[1])
-            .list_tags()
-            // Optional Parameters
-            .per_page(100)
-            .page(0u32)
-            // Send the request
-            .send()
-            .await
-        {
-            Ok(page) => page,
+async fn retrieve_releases(
+    tokens: &TokenPool,
+    limits: &Limits,
+    repository: &str,
+    parser: &TagParser,
+    max_pages: Option<u32>,
+    prefetched: &mut Prefetched,
+    validators: &mut Validators,
+) -> Result<Vec<VersionStruct>, LoadError> {
+    let route = format!("/repos/{}/releases?per_page=100&page=1", repository);
+    let items: Vec<Release> =
+        match collect_pages(tokens, limits, route, max_pages, prefetched, validators).await {
+            Ok(items) => items,
             Err(err) => {
-                eprintln!("Error fetching tags for {}: {}", repository, err);
-                eprintln!("This might be due to GitHub API rate limiting. Please wait and try again later.");
-                eprintln!("Consider setting up a GitHub token via GITHUB_TOKEN environment variable for higher rate limits.");
-                return Err(LoadError);
+                return Err(LoadError {
+                    cause: format!("fetching releases: {}", err),
+                });
             }
         };
 
-        for val in page.into_iter().rev() {
-            let mut unsplit_name = val.name;
-            if unsplit_name.is_empty() {
-                unsplit_name = val.commit.sha;
+    let mut versions: Vec<VersionStruct> = Vec::new();
+    for val in items.into_iter().rev() {
+        // the tag is the most structured, the release title is the fallback
+        let Some(name) = parser
+            .parse(&val.tag_name)
+            .or_else(|| val.name.as_deref().and_then(|title| parser.parse(title)))
+        else {
+            println!(
+                "Skipping release {} of {}, no version found",
+                val.tag_name, repository
+            );
+            continue;
+        };
+        // drafts have no archive to publish
+        let Some(zipball_url) = val.zipball_url else {
+            println!(
+                "Skipping release {} of {}, no zipball",
+                val.tag_name, repository
+            );
+            continue;
+        };
+        versions.push(VersionStruct {
+            name,
+            url: zipball_url.to_string(),
+            tag: Some(val.tag_name),
+            published_at: val.published_at,
+            ..Default::default()
+        });
+    }
+    Ok(versions)
+}
+
+async fn retrieve_tags(
+    tokens: &TokenPool,
+    limits: &Limits,
+    repository: &str,
+    parser: &TagParser,
+    max_pages: Option<u32>,
+    prefetched: &mut Prefetched,
+    validators: &mut Validators,
+) -> Result<Vec<VersionStruct>, LoadError> {
+    let route = format!("/repos/{}/tags?per_page=100&page=1", repository);
+    let items: Vec<Tag> =
+        match collect_pages(tokens, limits, route, max_pages, prefetched, validators).await {
+            Ok(items) => items,
+            Err(err) => {
+                return Err(LoadError {
+                    cause: format!("fetching tags: {}", err),
+                });
             }
-            let mut name = unsplit_name.as_str();
-            if unsplit_name.contains("v") {
-                (_, name) = unsplit_name.split_once("v").unwrap();
-            } else if unsplit_name.contains(" ") {
-                let splitted: Vec<&str> = unsplit_name.split(" ").collect();
-                name = splitted[splitted.len() - 1];
+        };
+
+    let mut versions: Vec<VersionStruct> = Vec::new();
+    for val in items.into_iter().rev() {
+        let Some(name) = parser.parse(&val.name) else {
+            println!(
+                "Skipping tag {} of {}, no version found",
+                val.name, repository
+            );
+            continue;
+        };
+        versions.push(VersionStruct {
+            name,
+            url: val.zipball_url.to_string(),
+            tag: Some(val.name),
+            ..Default::default()
+        });
+    }
+    Ok(versions)
+}
+
+// pages of listings already downloaded during this crawl, by route
+type Prefetched = HashMap<String, (Page<serde_json::Value>, Option<Validator>)>;
+
+// Whether every listing fetched by the last crawl is still the same, GitHub doesn't count the
+// 304 responses telling so against the rate limit. The first page that changed is kept in
+// `prefetched` so that listing the versions doesn't download it again.
+async fn listings_unchanged(
+    tokens: &TokenPool,
+    limits: &Limits,
+    validators: &Validators,
+    prefetched: &mut Prefetched,
+) -> bool {
+    for (route, validator) in validators.iter() {
+        match get_page(tokens, limits, route, Some(validator)).await {
+            Ok(None) => {}
+            Ok(Some(page)) => {
+                prefetched.insert(route.clone(), page);
+                return false;
             }
-            versions.push(VersionStruct {
-                name: name.to_string(),
-                url: val.zipball_url.to_string(),
-            });
+            Err(_) => return false,
         }
     }
+    true
+}
 
-    if repository == "morpho-org/metamorpho-v1.1"
-        || repository == "zeframlou/create3-factory"
-        || repository == "0xsequence/sstore2"
-        || repository == "huff-language/foundry-huff"
-        || repository == "a16z/halmos-cheatcodes"
-        || repository == "Uniswap/v4-periphery"
-        || repository == "transmissions11/solmate"
-        || repository == "boringcrypto/BoringSolidity"
-        || repository == "euler-xyz/euler-interfaces"
-        || repository == "pendle-finance/pendle-core-v2-public"
-        || repository == "Recon-Fuzz/setup-helpers"
-        || repository == "morpho-org/morpho-blue-oracles"
-    {
-        let mut main_branch = None;
-        let mut page_num = 1u32;
-
-        // Iterate through all pages to find main or master branch
-        loop {
-            let page = octocrab
-                .repos(split_versions[0], split_versions[1])
-                .list_branches()
-                .per_page(100)
-                .page(page_num)
-                .send()
-                .await
-                .unwrap();
-
-            // Look for main or master branch in current page
-            if let Some(branch) = page
-                .items
-                .iter()
-                .find(|b| b.name == "main" || b.name == "master")
-            {
-                main_branch = Some(branch.clone());
-                break;
-            }
+// A page of a listing, from `prefetched` when it was already downloaded
+async fn get_listing_page(
+    tokens: &TokenPool,
+    limits: &Limits,
+    route: &str,
+    prefetched: &mut Prefetched,
+) -> Result<Option<(Page<serde_json::Value>, Option<Validator>)>, String> {
+    match prefetched.remove(route) {
+        Some(page) => Ok(Some(page)),
+        None => get_page(tokens, limits, route, None).await,
+    }
+}
+
+fn parse_items<T: DeserializeOwned>(page: &mut Page<serde_json::Value>) -> Result<Vec<T>, String> {
+    page.take_items()
+        .into_iter()
+        .map(|item| serde_json::from_value(item).map_err(|err| err.to_string()))
+        .collect()
+}
+
+// Follows the `next` links of a listing, fetching at most `max_pages` pages when set
+async fn collect_pages<T: DeserializeOwned>(
+    tokens: &TokenPool,
+    limits: &Limits,
+    route: String,
+    max_pages: Option<u32>,
+    prefetched: &mut Prefetched,
+    validators: &mut Validators,
+) -> Result<Vec<T>, String> {
+    let mut items: Vec<T> = Vec::new();
+    let mut next = Some(route);
+    let mut fetched = 0u32;
+    while let Some(route) = next {
+        if fetched > 0 && max_pages.is_some_and(|max| fetched >= max) {
+            break;
+        }
+        let Some((mut page, validator)) =
+            get_listing_page(tokens, limits, &route, prefetched).await?
+        else {
+            break;
+        };
+        items.append(&mut parse_items(&mut page)?);
+        fetched += 1;
+        // relative so that the request stays authenticated
+        next = page
+            .next
+            .as_ref()
+            .and_then(|uri| uri.path_and_query())
+            .map(|route| route.to_string());
+        if let Some(validator) = validator {
+            validators.insert(route, validator);
+        }
+    }
+    Ok(items)
+}
+
+// Fetches a page of the GitHub API, waiting for the rate limit to reset instead of failing.
+// With a `validator` the page only comes back when it changed since, `None` otherwise.
+async fn get_page(
+    tokens: &TokenPool,
+    limits: &Limits,
+    route: &str,
+    validator: Option<&Validator>,
+) -> Result<Option<(Page<serde_json::Value>, Option<Validator>)>, String> {
+    let mut headers = HeaderMap::new();
+    if let Some(validator) = validator {
+        let etag = validator.etag.as_deref().map(HeaderValue::from_str);
+        if let Some(Ok(etag)) = etag {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+        let last_modified = validator
+            .last_modified
+            .as_deref()
+            .map(HeaderValue::from_str);
+        if let Some(Ok(last_modified)) = last_modified {
+            headers.insert(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    for _ in 0..MAX_RATE_LIMITED_ATTEMPTS {
+        let (index, octocrab) = tokens.pick();
+        // every token has its own quota, an exhausted one only pauses itself
+        let key = tokens.limiter_key(index, GITHUB_API_HOST);
+        limits.throttle(&key).await?;
+        let response = octocrab
+            ._get_with_headers(route, Some(headers.clone()))
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = response.status();
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        tokens.record(
+            index,
+            header("x-ratelimit-remaining").and_then(|value| value.parse().ok()),
+            header("x-ratelimit-reset").and_then(|value| value.parse().ok()),
+        );
+        let rate_limited = limits.observe(&key, status.as_u16(), header);
+        if rate_limited {
+            continue;
+        }
+        if status == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            let body = octocrab.body_to_string(response).await.unwrap_or_default();
+            return Err(format!("{} {}", status, body));
+        }
+        let validator = Validator::from_headers(header);
+        let page = Page::from_response(response)
+            .await
+            .map_err(|err| err.to_string())?;
+        return Ok(Some((page, validator)));
+    }
+    Err(format!(
+        "still rate limited after {} attempts",
+        MAX_RATE_LIMITED_ATTEMPTS
+    ))
+}
+
+// Snapshot of the head commit of `branch`, or of main/master when no branch is configured
+async fn retrieve_branch_head(
+    tokens: &TokenPool,
+    limits: &Limits,
+    repository: &str,
+    branch: Option<&str>,
+    prefetched: &mut Prefetched,
+    validators: &mut Validators,
+) -> Result<Vec<VersionStruct>, LoadError> {
+    let mut main_branch = None;
+    let mut page_num = 1u32;
 
-            // If no more pages, break
-            if page.items.len() < 100 {
-                break;
+    // Iterate through all pages to find the branch
+    loop {
+        let route = format!(
+            "/repos/{}/branches?per_page=100&page={}",
+            repository, page_num
+        );
+        let branches: Vec<Branch> = match get_listing_page(tokens, limits, &route, prefetched).await
+        {
+            Ok(Some((mut page, validator))) => {
+                if let Some(validator) = validator {
+                    validators.insert(route, validator);
+                }
+                parse_items(&mut page).map_err(|err| LoadError {
+                    cause: format!("parsing branches: {}", err),
+                })?
+            }
+            Ok(None) => break,
+            Err(err) => {
+                return Err(LoadError {
+                    cause: format!("fetching branches: {}", err),
+                });
             }
+        };
 
-            page_num += 1;
+        // Look for the configured branch, or main/master, in current page
+        if let Some(found) = branches.iter().find(|b| match branch {
+            Some(branch) => b.name == branch,
+            None => b.name == "main" || b.name == "master",
+        }) {
+            main_branch = Some(found.clone());
+            break;
         }
 
-        let main_branch = main_branch.unwrap_or_else(|| {
-            eprintln!(
-                "No main or master branch found for repository: {}",
-                repository
-            );
-            std::process::exit(1);
-        });
+        // If no more pages, break
+        if branches.len() < 100 {
+            break;
+        }
 
-        let commit_sha = main_branch.commit.sha.clone();
-        versions.push(VersionStruct {
-            name: commit_sha.clone(),
-            url: format!(
-                "https://api.github.com/repos/{}/{}/zipball/{}",
-                split_versions[0], split_versions[1], commit_sha
-            ),
-        });
+        page_num += 1;
     }
-    Ok(versions)
+
+    let Some(main_branch) = main_branch else {
+        return Err(LoadError {
+            cause: format!("no {} branch found", branch.unwrap_or("main or master")),
+        });
+    };
+
+    let commit_sha = main_branch.commit.sha.clone();
+    Ok(vec![VersionStruct {
+        name: commit_sha.clone(),
+        url: format!(
+            "https://api.github.com/repos/{}/zipball/{}",
+            repository, commit_sha
+        ),
+        ..Default::default()
+    }])
 }
 
+// Downloads the zipball of `version` to `archive`, retrying with the tag ref when the API can't resolve it
 pub async fn download_dependency(
-    dependency_name: &str,
+    client: &Client,
+    tokens: &TokenPool,
+    limits: &Limits,
     version: &VersionStruct,
+    archive: &Path,
 ) -> Result<(), DownloadError> {
-    let dependency_directory: PathBuf = get_current_working_dir().unwrap().join("github");
-    if !dependency_directory.is_dir() {
-        fs::create_dir(&dependency_directory).unwrap();
+    if let Some(dependency_directory) = archive.parent() {
+        fs::create_dir_all(dependency_directory)?;
     }
 
-    let mut dst = Vec::new();
-    let mut easy = Easy::new();
-    easy.url(&version.url).unwrap();
-    let mut list = List::new();
-    list.append("User-Agent: Mozilla/5.0 (platform; rv:geckoversion) Gecko/geckotrail Firefox/firefoxversion").unwrap();
-    easy.http_headers(list).unwrap();
-    let _redirect = easy.follow_location(true);
-
-    {
-        let mut transfer = easy.transfer();
-        transfer
-            .write_function(|data| {
-                dst.extend_from_slice(data);
-                Ok(data.len())
-            })
-            .unwrap();
-        transfer.perform().unwrap();
-    }
-    {
-        let zip_path = format!("{}-{}.zip", &dependency_name, &version.name);
-        // Try to decode the response data as a string to check for error messages
-        if let Ok(response_str) = String::from_utf8(dst.clone()) {
-            if response_str.contains("\"message\"") && response_str.contains("\"status\"") {
-                let mut new_version = version.clone();
-                new_version.url = new_version
-                    .url
-                    .clone()
-                    .replace("/zipball/", "/zipball/refs/tags/");
-                return Box::pin(download_dependency(dependency_name, &new_version)).await;
-            }
+    let Some(zipball) = fetch(client, tokens, limits, &version.url).await? else {
+        if version.url.contains("/zipball/refs/tags/") {
+            return Err(DownloadError {
+                cause: format!("{} not found", version.url),
+            });
         }
-        let mut file = File::create(dependency_directory.join(zip_path)).unwrap();
-        let _ = file.write_all(dst.as_slice());
-    }
+        let mut new_version = version.clone();
+        new_version.url = new_version
+            .url
+            .clone()
+            .replace("/zipball/", "/zipball/refs/tags/");
+        return Box::pin(download_dependency(
+            client,
+            tokens,
+            limits,
+            &new_version,
+            archive,
+        ))
+        .await;
+    };
+    let mut file = File::create(archive)?;
+    file.write_all(zipball.as_slice())?;
     Ok(())
 }
 
-pub fn unzip_dependency(
-    dependency_name: &String,
-    dependency_version: &String,
-) -> Result<(), UnzippingError> {
-    let file_name: String = format!("{}-{}.zip", dependency_name, dependency_version);
-    let target_dep_version = format_version(dependency_name, dependency_version);
-    let target_name: String = format!("{}-{}/", dependency_name, target_dep_version);
-    let current_dir: PathBuf = get_current_working_dir()
-        .unwrap()
-        .join(Path::new(&("github/".to_owned() + &file_name)));
-
-    let target = get_current_working_dir()
-        .unwrap()
-        .join("github/")
-        .join(target_name);
-    let archive: Vec<u8> = read_file(current_dir.as_path().to_str().unwrap().to_string()).unwrap();
-    match zip_extract::extract(Cursor::new(archive), &target, true) {
-        Ok(_) => {}
-        Err(_) => {
-            return Err(UnzippingError {
-                name: dependency_name.to_string(),
-                version: dependency_version.to_string(),
-            })
+// Downloads `url` with one of the tokens, waiting for the rate limit to reset instead of failing.
+// `None` when the API can't find it.
+async fn fetch(
+    client: &Client,
+    tokens: &TokenPool,
+    limits: &Limits,
+    url: &str,
+) -> Result<Option<Vec<u8>>, DownloadError> {
+    let host = host_of(url);
+    for _ in 0..MAX_RATE_LIMITED_ATTEMPTS {
+        let (index, _) = tokens.pick();
+        // zipballs count against the quota of the token like any other API request
+        let key = tokens.limiter_key(index, &host);
+        limits
+            .throttle(&key)
+            .await
+            .map_err(|cause| DownloadError { cause })?;
+        let mut request = client.get(url);
+        // reqwest drops the token when following the redirect to codeload.github.com
+        if let Some(token) = tokens.token(index) {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        tokens.record(
+            index,
+            header("x-ratelimit-remaining").and_then(|value| value.parse().ok()),
+            header("x-ratelimit-reset").and_then(|value| value.parse().ok()),
+        );
+        let rate_limited = limits.observe(&key, status.as_u16(), header);
+        if rate_limited {
+            continue;
+        }
+        if status.as_u16() == 404 {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(DownloadError {
+                cause: format!("{} {}", status, body),
+            });
         }
+        return Ok(Some(response.bytes().await?.to_vec()));
+    }
+    Err(DownloadError {
+        cause: format!(
+            "still rate limited after {} attempts",
+            MAX_RATE_LIMITED_ATTEMPTS
+        ),
+    })
+}
+
+// Extracts `archive` into `target` without blocking the other crawls
+pub async fn unzip_dependency(archive: &Path, target: &Path) -> Result<(), UnzippingError> {
+    let archive_path = archive.to_path_buf();
+    let target_path = target.to_path_buf();
+    let extracted = tokio::task::spawn_blocking(move || {
+        let archive: Vec<u8> = read_file(&archive_path).map_err(|err| err.to_string())?;
+        zip_extract::extract(Cursor::new(archive), &target_path, true)
+            .map_err(|err| err.to_string())
+    })
+    .await
+    .map_err(|err| err.to_string())
+    .and_then(|extracted| extracted);
+    if let Err(cause) = extracted {
+        return Err(UnzippingError { cause });
     }
-    println!(
-        "The dependency {}-{} was unzipped!",
-        dependency_name, dependency_version
-    );
+    println!("{} was unzipped!", target.display());
     Ok(())
 }
 
-pub fn read_file(path: String) -> Result<Vec<u8>, std::io::Error> {
+pub fn read_file(path: &Path) -> Result<Vec<u8>, std::io::Error> {
     let f = File::open(path)?;
     let mut reader = BufReader::new(f);
     let mut buffer = Vec::new();
@@ -311,17 +723,66 @@ pub fn read_file(path: String) -> Result<Vec<u8>, std::io::Error> {
 
 #[derive(Deserialize, Debug)]
 struct Data {
-    github: Vec<String>,
+    github: Vec<RepositoryEntry>,
 }
 
 #[derive(Debug, Clone)]
-pub struct LoadError;
+pub struct DownloadError {
+    pub cause: String,
+}
 
-#[derive(Debug, Clone)]
-pub struct DownloadError;
+impl From<std::io::Error> for DownloadError {
+    fn from(err: std::io::Error) -> Self {
+        DownloadError {
+            cause: err.to_string(),
+        }
+    }
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(err: reqwest::Error) -> Self {
+        DownloadError {
+            cause: err.to_string(),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct UnzippingError {
-    pub name: String,
-    pub version: String,
+    pub cause: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_items_reads_listing_pages() {
+        let mut page: Page<serde_json::Value> = Page::default();
+        page.items = vec![serde_json::json!({
+            "name": "v1.2.3",
+            "commit": {
+                "sha": "c5a1e1d0c8d5a3e4f2b1a0c9d8e7f6a5b4c3d2e1",
+                "url": "https://api.github.com/repos/foundry-rs/forge-std/commits/c5a1e1d"
+            },
+            "zipball_url": "https://api.github.com/repos/foundry-rs/forge-std/zipball/v1.2.3",
+            "tarball_url": "https://api.github.com/repos/foundry-rs/forge-std/tarball/v1.2.3",
+            "node_id": "REF_kwDOAbc"
+        })];
+        let tags: Vec<Tag> = parse_items(&mut page).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].name, "v1.2.3");
+        assert!(page.items.is_empty());
+
+        page.items = vec![serde_json::json!({ "name": 7 })];
+        assert!(parse_items::<Tag>(&mut page).is_err());
+    }
+
+    #[test]
+    fn archives_sit_next_to_their_directory() {
+        assert_eq!(
+            archive_path(Path::new("/work/github/forge-std~1.2.3")),
+            PathBuf::from("/work/github/forge-std~1.2.3.zip")
+        );
+    }
 }
@@ -0,0 +1,263 @@
+use crate::config::{
+    load_settings, DEFAULT_CONCURRENCY, DEFAULT_HOST_CONCURRENCY, DEFAULT_REQUESTS_PER_SECOND,
+};
+use crate::shutdown::Shutdown;
+use chrono::Utc;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// how often a request turned down by a rate limit is sent again before giving up
+pub const MAX_RATE_LIMITED_ATTEMPTS: usize = 3;
+// pause after a 429 that doesn't say for how long, GitHub asks for at least a minute
+const DEFAULT_RATE_LIMIT_PAUSE_SECONDS: u64 = 60;
+
+/// How much a crawl does at once.
+///
+/// Up to `concurrency` repositories are crawled side by side, but no more than `host_concurrency`
+/// downloads or pushes hit the same host at the same time. Every host also gets a token bucket of
+/// `requests_per_second`, paused whenever the host says its rate limit is exhausted.
+pub struct Limits {
+    pub concurrency: usize,
+    pub host_concurrency: usize,
+    pub requests_per_second: f64,
+    hosts: Mutex<HashMap<String, Host>>,
+    // a host can stay paused for an hour, nothing waits for it once a shutdown is requested
+    shutdown: Arc<Shutdown>,
+}
+
+struct Host {
+    transfers: Arc<Semaphore>,
+    tokens: f64,
+    refilled_at: Instant,
+    // set from the rate limit headers of the host
+    paused_until: Option<Instant>,
+}
+
+impl Host {
+    // Takes a token, or returns how long to wait before one is available
+    fn take(&mut self, requests_per_second: f64) -> Option<Duration> {
+        let now = Instant::now();
+        if let Some(paused_until) = self.paused_until {
+            if paused_until > now {
+                return Some(paused_until - now);
+            }
+            self.paused_until = None;
+        }
+        let elapsed = (now - self.refilled_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * requests_per_second).min(requests_per_second.max(1.0));
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            (1.0 - self.tokens) / requests_per_second,
+        ))
+    }
+}
+
+impl Limits {
+    // Reads the limits from repositories.toml, `concurrency` from the command line wins over it
+    pub fn load(concurrency: Option<usize>, shutdown: Arc<Shutdown>) -> Result<Self, String> {
+        let settings = load_settings().map_err(|err| format!("loading settings: {}", err))?;
+        let concurrency = concurrency
+            .or(settings.concurrency)
+            .unwrap_or(DEFAULT_CONCURRENCY);
+        let host_concurrency = settings
+            .host_concurrency
+            .unwrap_or(DEFAULT_HOST_CONCURRENCY);
+        if concurrency == 0 || host_concurrency == 0 {
+            return Err(format!(
+                "invalid concurrency {} (per host {})",
+                concurrency, host_concurrency
+            ));
+        }
+        let requests_per_second = settings
+            .requests_per_second
+            .unwrap_or(DEFAULT_REQUESTS_PER_SECOND);
+        if !requests_per_second.is_finite() || requests_per_second <= 0.0 {
+            return Err(format!(
+                "invalid requests_per_second {}",
+                requests_per_second
+            ));
+        }
+        Ok(Limits {
+            concurrency,
+            host_concurrency,
+            requests_per_second,
+            hosts: Mutex::new(HashMap::new()),
+            shutdown,
+        })
+    }
+
+    // Takes the settings of `reloaded`, the hosts keep their pauses and request buckets.
+    // Only called between crawls, when no transfer holds a permit.
+    pub fn update(&mut self, reloaded: Limits) {
+        self.concurrency = reloaded.concurrency;
+        self.requests_per_second = reloaded.requests_per_second;
+        if self.host_concurrency != reloaded.host_concurrency {
+            self.host_concurrency = reloaded.host_concurrency;
+            for host in self.hosts.get_mut().unwrap().values_mut() {
+                host.transfers = Arc::new(Semaphore::new(self.host_concurrency));
+            }
+        }
+    }
+
+    fn with_host<T>(&self, host: &str, f: impl FnOnce(&mut Host) -> T) -> T {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.entry(host.to_string()).or_insert_with(|| Host {
+            transfers: Arc::new(Semaphore::new(self.host_concurrency)),
+            tokens: self.requests_per_second.max(1.0),
+            refilled_at: Instant::now(),
+            paused_until: None,
+        });
+        f(host)
+    }
+
+    // Waits until `host` has room for one more download or push, which lasts as long as the permit
+    pub async fn acquire(&self, host: &str) -> OwnedSemaphorePermit {
+        let semaphore = self.with_host(host, |host| host.transfers.clone());
+        // the semaphore is never closed
+        semaphore.acquire_owned().await.unwrap()
+    }
+
+    // Waits until a request can be sent to `host` without going over its rate, fails when a
+    // shutdown is requested in the meantime
+    pub async fn throttle(&self, host: &str) -> Result<(), String> {
+        while let Some(wait) = self.with_host(host, |host| host.take(self.requests_per_second)) {
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = self.shutdown.wait() => {
+                    return Err(format!("shutting down while waiting for {}", host));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Pauses `host` as told by the `retry-after` and `x-ratelimit-*` headers of a response, and
+    // returns whether the request was turned down because of the rate limit and can be sent again
+    pub fn observe(
+        &self,
+        host: &str,
+        status: u16,
+        header: impl Fn(&str) -> Option<String>,
+    ) -> bool {
+        let retry_after: Option<u64> =
+            header("retry-after").and_then(|value| value.trim().parse().ok());
+        let remaining: Option<u64> =
+            header("x-ratelimit-remaining").and_then(|value| value.trim().parse().ok());
+        let reset: Option<i64> =
+            header("x-ratelimit-reset").and_then(|value| value.trim().parse().ok());
+
+        let pause = match (retry_after, remaining, reset) {
+            (Some(retry_after), _, _) => Some(retry_after),
+            // `reset` is in seconds since the epoch, one more second makes up for clock skew
+            (None, Some(0), Some(reset)) => {
+                Some((reset - Utc::now().timestamp()).max(0) as u64 + 1)
+            }
+            _ if status == 429 => Some(DEFAULT_RATE_LIMIT_PAUSE_SECONDS),
+            _ => None,
+        };
+        let Some(pause) = pause else {
+            return false;
+        };
+        let rate_limited =
+            status == 429 || (status == 403 && (retry_after.is_some() || remaining == Some(0)));
+        if rate_limited {
+            println!("Rate limited by {}, pausing for {}s", host, pause);
+        }
+        let paused_until = Instant::now() + Duration::from_secs(pause);
+        self.with_host(host, |host| {
+            host.paused_until = host.paused_until.max(Some(paused_until));
+        });
+        rate_limited
+    }
+}
+
+// the host `url` points to, the whole string when it isn't a URL
+pub fn host_of(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(url) => url.host_str().unwrap_or_default().to_string(),
+        Err(_) => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(host_concurrency: usize, shutdown: Arc<Shutdown>) -> Limits {
+        Limits {
+            concurrency: 1,
+            host_concurrency,
+            requests_per_second: 1000.0,
+            hosts: Mutex::new(HashMap::new()),
+            shutdown,
+        }
+    }
+
+    fn paused_for(limits: &Limits, host: &str) -> Option<Duration> {
+        limits.with_host(host, |host| {
+            host.paused_until
+                .map(|paused_until| paused_until - Instant::now())
+        })
+    }
+
+    #[test]
+    fn observe_pauses_rate_limited_hosts() {
+        let limits = limits(1, Arc::default());
+        let headers = |name: &str| (name == "retry-after").then(|| "120".to_string());
+        assert!(limits.observe("api.github.com", 429, headers));
+        let paused = paused_for(&limits, "api.github.com").unwrap();
+        assert!(paused > Duration::from_secs(110) && paused <= Duration::from_secs(120));
+
+        // an exhausted quota pauses until it resets, the request went through though
+        let reset = (Utc::now().timestamp() + 30).to_string();
+        let headers = |name: &str| match name {
+            "x-ratelimit-remaining" => Some("0".to_string()),
+            "x-ratelimit-reset" => Some(reset.clone()),
+            _ => None,
+        };
+        assert!(!limits.observe("registry.npmjs.org", 200, headers));
+        assert!(paused_for(&limits, "registry.npmjs.org").is_some());
+        assert!(limits.observe("registry.npmjs.org", 403, headers));
+
+        assert!(!limits.observe("codeload.github.com", 200, |_| None));
+        assert_eq!(paused_for(&limits, "codeload.github.com"), None);
+    }
+
+    #[tokio::test]
+    async fn throttle_gives_up_on_shutdown() {
+        let shutdown = Arc::new(Shutdown::default());
+        let limits = limits(1, shutdown.clone());
+        limits.observe("api.github.com", 429, |_| None);
+        let throttled = limits.throttle("api.github.com");
+        shutdown.request();
+        let throttled = tokio::time::timeout(Duration::from_secs(5), throttled).await;
+        assert!(throttled.unwrap().is_err());
+        // hosts that don't need to wait still go through
+        assert!(limits.throttle("registry.npmjs.org").await.is_ok());
+    }
+
+    #[test]
+    fn update_keeps_the_state_of_hosts() {
+        let mut current = limits(1, Arc::default());
+        current.observe("api.github.com", 429, |_| None);
+        current.update(Limits {
+            concurrency: 4,
+            requests_per_second: 5.0,
+            ..limits(3, Arc::default())
+        });
+        assert_eq!(current.concurrency, 4);
+        assert_eq!(current.requests_per_second, 5.0);
+        assert!(paused_for(&current, "api.github.com").is_some());
+        let permits =
+            current.with_host("api.github.com", |host| host.transfers.available_permits());
+        assert_eq!(permits, 3);
+    }
+}
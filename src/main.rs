@@ -1,193 +1,137 @@
+mod commands;
+mod config;
+mod crawl;
+mod daemon;
 mod db;
 mod github;
+mod limits;
 mod manager;
 mod npm;
+mod schedule;
+mod shutdown;
+mod source;
+mod tokens;
 mod utils;
+mod version;
 
-use chrono::Utc;
-use db::{
-    get_invalid_versions_for_repo_from_db, get_repositories_not_updated_in_last_hour,
-    get_versions_for_repo_from_db, insert_version_into_db, Version,
-};
-use github::{download_dependency, github_retrieve_versions, unzip_dependency};
-use manager::{github_push_to_repository_remote, npm_push_to_repository_remote};
-use npm::LoadError;
-use npm::{npm_retrieve_versions, retrieve_version};
-use rusqlite::Error;
-use std::env;
-use std::fs::{remove_dir_all, remove_file};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use commands::{CrawlOptions, Target, EXIT_INVALID};
+use db::Store;
+use shutdown::{listen_for_signals, Shutdown};
+use std::path::PathBuf;
 use std::process::exit;
-use std::thread::sleep;
-use std::time::Duration;
-use utils::{format_dependency_name, format_version, get_current_working_dir};
+use std::sync::Arc;
+use utils::get_db_path;
 
-#[tokio::main]
-async fn main() {
-    let target = env::args().nth(1);
-    if target.is_none() {
-        println!("Argument failed, should be npm or github");
-        exit(1);
-    }
-    let source = target.unwrap();
-    let all_repositories: Vec<String> = if source == "npm" {
-        npm::load_repositories()
-            .map_err(|err: LoadError| {
-                println!("{:?}", err);
-            })
-            .unwrap()
-    } else {
-        match github::load_repositories() {
-            Ok(repo) => repo,
-            Err(err) => {
-                eprintln!("Err {:?}", err);
-                exit(1)
-            }
-        }
-    };
+#[derive(Parser, Debug)]
+#[command(
+    about = "Crawls npm and GitHub for new versions and publishes them on Soldeer",
+    after_help = "Exit codes: 0 on success, 1 when something failed (e.g. a repository couldn't be \
+                  crawled), 2 on invalid arguments or configuration"
+)]
+struct Args {
+    /// The crawler database, defaults to CRAWLER_DB or repositories.db
+    #[arg(long, global = true)]
+    db: Option<PathBuf>,
 
-    // Filter repositories that haven't been updated in the last hour
-    let repositories = match get_repositories_not_updated_in_last_hour(all_repositories) {
-        Ok(repos) => {
-            println!(
-                "Found {} repositories that need updating (not updated in the last hour)",
-                repos.len()
-            );
-            repos
-        }
-        Err(err) => {
-            eprintln!("Error filtering repositories: {:?}", err);
-            exit(1);
-        }
-    };
+    #[command(subcommand)]
+    command: Command,
+}
 
-    for repository in repositories {
-        sleep(Duration::from_millis(1000));
-        let existing_versions: Vec<String> = get_versions_for_repo_from_db(repository.clone())
-            .map_err(|err: Error| {
-                println!("{:?}", err);
-            })
-            .unwrap();
-        let invalid_versions: Vec<String> =
-            get_invalid_versions_for_repo_from_db(repository.clone())
-                .map_err(|err: Error| {
-                    println!("{:?}", err);
-                })
-                .unwrap();
-        let versions: Vec<VersionStruct> = if source == "npm" {
-            npm_retrieve_versions(&repository)
-                .map_err(|err: LoadError| {
-                    println!("{:?}", err);
-                })
-                .unwrap()
-        } else {
-            github_retrieve_versions(&repository).await.unwrap()
-        };
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Crawl once for new versions and publish them
+    Crawl {
+        /// Where to look for new versions, `all` crawls every source in one run
+        #[arg(value_enum)]
+        target: Target,
 
-        let versions_is_empty = versions.is_empty();
+        #[command(flatten)]
+        options: CrawlOptions,
+    },
+    /// Keep crawling npm and GitHub as repositories become due, until SIGINT or SIGTERM
+    Daemon {
+        /// Default time between two checks of a repository, e.g. `15m`, `1h` or `7d`
+        #[arg(long)]
+        interval: Option<String>,
 
-        for version in versions.into_iter() {
-            if existing_versions.contains(&version.name) || invalid_versions.contains(&version.name)
-            {
-                continue;
-            }
-            if source == "npm" {
-                match retrieve_version(&repository, &version) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        continue;
-                    }
-                }
-                match npm_push_to_repository_remote(&repository, &version.name).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        continue;
-                    }
-                }
-            } else {
-                let dependency_name = &format_dependency_name(&repository);
-                match download_dependency(dependency_name, &version).await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        eprint!("Error on downloading dependency {} {:?}", &repository, err);
-                        exit(1);
-                    }
-                }
+        /// How many repositories are crawled at the same time
+        #[arg(long)]
+        concurrency: Option<usize>,
+    },
+    /// List the published and invalid versions of a repository
+    ListVersions { repository: String },
+    /// Never crawl a version of a repository again
+    MarkInvalid {
+        repository: String,
+        version: String,
 
-                match unzip_dependency(&dependency_name.to_string(), &version.name) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        eprintln!("Error unzipping {} {}", dependency_name, &version.name);
-                        exit(1);
-                    }
-                }
-                let formatted_version = format_version(dependency_name, &version.name);
-                match github_push_to_repository_remote(
-                    &dependency_name.to_string(),
-                    &formatted_version,
-                )
-                .await
-                {
-                    Ok(_) => {}
-                    Err(err) => {
-                        if err.cause.contains("dependency already exists") {
-                            let version_to_insert: Version = Version {
-                                repository: repository.clone(),
-                                version: version.name.clone(),
-                                last_updated: Utc::now(),
-                            };
+        /// Why the version is invalid
+        #[arg(long)]
+        error: Option<String>,
+    },
+    /// Crawl an invalid version again on the next run
+    Retry { repository: String, version: String },
+    /// Show the crawl state of the configured repositories
+    Status {
+        /// Only show this repository
+        repository: Option<String>,
+    },
+    /// Check repositories.toml without crawling anything
+    ValidateConfig,
+}
 
-                            insert_version_into_db(version_to_insert)
-                                .map_err(|err: Error| {
-                                    println!("{:?}", err);
-                                })
-                                .unwrap();
-                        }
-                        continue;
-                    }
-                }
-            }
-            let version_to_insert: Version = Version {
-                repository: repository.clone(),
-                version: version.name.clone(),
-                last_updated: Utc::now(),
-            };
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    if let Command::ValidateConfig = args.command {
+        exit(commands::validate_config());
+    }
 
-            insert_version_into_db(version_to_insert)
-                .map_err(|err: Error| {
-                    println!("{:?}", err);
-                })
-                .unwrap();
-            if source == "npm"
-                && get_current_working_dir()
-                    .unwrap()
-                    .join("node_modules")
-                    .exists()
-            {
-                remove_dir_all(get_current_working_dir().unwrap().join("node_modules")).unwrap();
-                remove_file(get_current_working_dir().unwrap().join("package.json")).unwrap();
-                remove_file(get_current_working_dir().unwrap().join("package-lock.json")).unwrap();
-            }
+    let store = match Store::open(&args.db.unwrap_or_else(get_db_path)) {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("Error opening the database: {:?}", err);
+            exit(EXIT_INVALID);
         }
+    };
+    let shutdown = Arc::new(Shutdown::default());
+    listen_for_signals(shutdown.clone());
 
-        // if we don't have any version, still update the last updated time
-        if versions_is_empty {
-            let version_to_insert: Version = Version {
-                repository: repository.clone(),
-                version: "".to_string(),
-                last_updated: Utc::now(),
-            };
-            insert_version_into_db(version_to_insert)
-                .map_err(|err: Error| {
-                    println!("{:?}", err);
-                })
-                .unwrap();
+    let code = match args.command {
+        Command::Crawl { target, options } => {
+            commands::crawl(&store, target, options, &shutdown).await
         }
-    }
+        Command::Daemon {
+            interval,
+            concurrency,
+        } => commands::daemon(&store, interval, concurrency, &shutdown).await,
+        Command::ListVersions { repository } => commands::list_versions(&store, &repository),
+        Command::MarkInvalid {
+            repository,
+            version,
+            error,
+        } => commands::mark_invalid(&store, &repository, &version, error),
+        Command::Retry {
+            repository,
+            version,
+        } => commands::retry(&store, &repository, &version),
+        Command::Status { repository } => commands::status(&store, repository.as_deref()),
+        Command::ValidateConfig => commands::validate_config(),
+    };
+    exit(code);
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 
 pub struct VersionStruct {
     pub name: String,
     pub url: String,
+    pub published_at: Option<DateTime<Utc>>,
+    // the git tag the version was parsed from
+    pub tag: Option<String>,
+    // npm `dist.integrity` and `dist.shasum` of the tarball behind `url`
+    pub integrity: Option<String>,
+    pub shasum: Option<String>,
 }
@@ -1,6 +1,14 @@
+use crate::limits::host_of;
+use async_trait::async_trait;
 use soldeer_commands::commands::push::Push;
-
-use crate::utils::get_current_working_dir;
+use soldeer_core::errors::{PublishError, SoldeerError};
+use soldeer_core::registry::api_url;
+use std::fs::{create_dir_all, remove_file, File};
+use std::io::{ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+use zip::result::ZipError;
+use zip::write::FileOptions;
 // use std::thread;
 
 // pub fn zip_version(repository: &String, version: &String) {
@@ -44,47 +52,6 @@ use crate::utils::get_current_working_dir;
 //     remove_dir_all(get_current_working_dir().unwrap().join("node_modules/")).unwrap();
 // }
 
-// simple zip directory that walks through a directory and zips it by adding every file to the zip archive
-// fn zip_dir<T>(
-//     it: &mut dyn Iterator<Item = DirEntry>,
-//     prefix: &str,
-//     writer: T,
-//     method: zip::CompressionMethod,
-// ) -> zip::result::ZipResult<()>
-// where
-//     T: Write + Seek,
-// {
-//     let mut zip: zip::ZipWriter<T> = zip::ZipWriter::new(writer);
-//     let options: FileOptions = FileOptions::default()
-//         .compression_method(method)
-//         .unix_permissions(0o755);
-
-//     let mut buffer: Vec<u8> = Vec::new();
-//     for entry in it {
-//         let path: &Path = entry.path();
-//         let name: &Path = path.strip_prefix(Path::new(prefix)).unwrap();
-
-//         // Write file or directory explicitly
-//         // Some unzip tools unzip files with directory paths correctly, some do not!
-//         if path.is_file() {
-//             // println!("adding file {:?} as {:?} ...", path, name);
-//             zip.start_file::<&str>(name.to_str().unwrap(), options)?;
-//             let mut f: File = File::open(path)?;
-
-//             f.read_to_end(&mut buffer)?;
-//             zip.write_all(&*buffer)?;
-//             buffer.clear();
-//         } else if name.as_os_str().len() != 0 {
-//             // Only if not root! Avoids path spec / warning
-//             // and mapname conversion failed error on unzip
-//             // println!("adding dir {:?} as {:?} ...", path, name);
-//             zip.add_directory(name.to_str().unwrap(), options)?;
-//         }
-//     }
-//     zip.finish()?;
-//     Result::Ok(())
-// }
-
 // pub fn push_to_repository(repository: &String, version: &String) {
 //     println!("Pushing {}/{} to repository", repository, version);
 //     let commit_message: String = format!(
@@ -109,53 +76,113 @@ use crate::utils::get_current_working_dir;
 //     );
 // }
 
-pub async fn npm_push_to_repository_remote(
-    repository: &String,
-    version: &String,
-) -> Result<(), PushError> {
-    println!("Pushing {}/{} to repository", repository, version);
-    let repo = repository.replace("/", "-").replace(".", "-");
+/// Where crawled versions end up once they have been materialized on disk.
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    // where versions are pushed to, pushes to the same host are capped together
+    fn host(&self) -> String;
 
-    let push = Push::builder()
-        .dependency(repo.clone() + "~" + version)
-        .path(
-            get_current_working_dir()
-                .unwrap()
-                .join("node_modules")
-                .join(repository.clone()),
-        )
-        .dry_run(false)
-        .skip_warnings(true)
-        .build()
-        .into();
+    async fn publish(
+        &self,
+        dependency_name: &str,
+        version: &str,
+        path: PathBuf,
+    ) -> Result<(), PushError>;
+}
 
-    match soldeer_commands::run(push).await {
-        Ok(_) => {}
-        Err(err) => {
-            eprintln!("{:?}", err);
+// Pushes to the live Soldeer registry
+pub struct SoldeerPublisher;
+
+#[async_trait]
+impl Publisher for SoldeerPublisher {
+    fn host(&self) -> String {
+        host_of(api_url("", &[]).as_str())
+    }
+
+    async fn publish(
+        &self,
+        dependency_name: &str,
+        version: &str,
+        path: PathBuf,
+    ) -> Result<(), PushError> {
+        push_to_repository_remote(dependency_name, version, path).await
+    }
+}
+
+// Writes `name~version.zip` archives into a local directory, used for staging and tests
+pub struct LocalPublisher {
+    pub directory: PathBuf,
+}
+
+#[async_trait]
+impl Publisher for LocalPublisher {
+    fn host(&self) -> String {
+        "localhost".to_string()
+    }
+
+    async fn publish(
+        &self,
+        dependency_name: &str,
+        version: &str,
+        path: PathBuf,
+    ) -> Result<(), PushError> {
+        println!(
+            "Writing {}~{} to {}",
+            dependency_name,
+            version,
+            self.directory.display()
+        );
+        if !self.directory.is_dir() {
+            if let Err(err) = create_dir_all(&self.directory) {
+                return Err(PushError {
+                    cause: format!("Local publish error {}", err),
+                    kind: PushErrorKind::Rejected,
+                });
+            }
+        }
+        let final_zip: PathBuf = self
+            .directory
+            .join(format!("{}~{}.zip", dependency_name, version));
+        // mirror the registry so that already published versions are recorded as such
+        if final_zip.exists() {
             return Err(PushError {
-                cause: "Soldeer push error".to_string(),
+                cause: "dependency already exists".to_string(),
+                kind: PushErrorKind::AlreadyExists,
             });
         }
-    };
-    Ok(())
+        let file: File = match File::create(&final_zip) {
+            Ok(file) => file,
+            Err(err) => {
+                return Err(PushError {
+                    cause: format!("Local publish error {}", err),
+                    kind: PushErrorKind::Rejected,
+                });
+            }
+        };
+        match zip_dir(&path, file, zip::CompressionMethod::Deflated) {
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("{:?}", err);
+                let _ = remove_file(&final_zip);
+                return Err(PushError {
+                    cause: format!("Local publish error {}", err),
+                    kind: PushErrorKind::Rejected,
+                });
+            }
+        };
+        Ok(())
+    }
 }
 
-pub async fn github_push_to_repository_remote(
-    dependency_name: &String,
-    version: &String,
+pub async fn push_to_repository_remote(
+    dependency_name: &str,
+    version: &str,
+    path: PathBuf,
 ) -> Result<(), PushError> {
     println!("Pushing {}/{} to repository", dependency_name, version);
-    let repo = dependency_name.replace("/", "-").replace(".", "-");
-    let full_dependency_name = format!("{}-{}", dependency_name, &version);
     let push = Push::builder()
-        .dependency(repo.clone() + "~" + version)
-        .path(
-            get_current_working_dir()
-                .unwrap()
-                .join("github")
-                .join(full_dependency_name),
-        )
+        .dependency(format!("{}~{}", dependency_name, version))
+        .path(path)
         .dry_run(false)
         .skip_warnings(true)
         .build()
@@ -164,15 +191,132 @@ pub async fn github_push_to_repository_remote(
         Ok(_) => {}
         Err(err) => {
             eprintln!("{:?}", err);
+            let kind = match &err {
+                SoldeerError::PublishError(PublishError::AlreadyExists) => {
+                    PushErrorKind::AlreadyExists
+                }
+                SoldeerError::PublishError(
+                    PublishError::InvalidName | PublishError::ProjectNotFound,
+                ) => PushErrorKind::NameRejected,
+                _ => PushErrorKind::Rejected,
+            };
             return Err(PushError {
                 cause: format!("Soldeer push error {}", err),
+                kind,
             });
         }
     };
     Ok(())
 }
 
+// simple zip directory that walks through a directory and zips it by adding every file to the zip archive
+fn zip_dir<T>(
+    source: &Path,
+    writer: T,
+    method: zip::CompressionMethod,
+) -> zip::result::ZipResult<()>
+where
+    T: Write + Seek,
+{
+    let mut zip: zip::ZipWriter<T> = zip::ZipWriter::new(writer);
+    let options: FileOptions = FileOptions::default()
+        .compression_method(method)
+        .unix_permissions(0o755);
+
+    let mut buffer: Vec<u8> = Vec::new();
+    for entry in WalkDir::new(source).into_iter().filter_map(|e| e.ok()) {
+        let path: &Path = entry.path();
+        let name: &Path = path.strip_prefix(source).unwrap();
+        // zip entries are named in UTF-8, fail the archive rather than mangle the name
+        let Some(name) = name.to_str() else {
+            return Err(ZipError::Io(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a valid UTF-8 file name", path.display()),
+            )));
+        };
+
+        // Write file or directory explicitly
+        // Some unzip tools unzip files with directory paths correctly, some do not!
+        if path.is_file() {
+            zip.start_file::<&str>(name, options)?;
+            let mut f: File = File::open(path)?;
+
+            f.read_to_end(&mut buffer)?;
+            zip.write_all(&buffer)?;
+            buffer.clear();
+        } else if !name.is_empty() {
+            // Only if not root! Avoids path spec / warning
+            // and mapname conversion failed error on unzip
+            zip.add_directory(name, options)?;
+        }
+    }
+    zip.finish()?;
+    Result::Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct PushError {
     pub cause: String,
+    pub kind: PushErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushErrorKind {
+    // the version is already on the registry
+    AlreadyExists,
+    // the registry doesn't know the dependency name or refuses it
+    NameRejected,
+    Rejected,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::io::Cursor;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn work_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("crawler-manager-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn zip_dir_adds_files_and_directories() {
+        let dir = work_dir("zip");
+        create_dir_all(dir.join("src")).unwrap();
+        write(dir.join("src/Counter.sol"), "contract Counter {}").unwrap();
+        let mut archive = Cursor::new(Vec::new());
+        zip_dir(&dir, &mut archive, zip::CompressionMethod::Deflated).unwrap();
+        let mut archive = zip::ZipArchive::new(archive).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(names, ["src/", "src/Counter.sol"]);
+        let mut contents = String::new();
+        archive
+            .by_name("src/Counter.sol")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "contract Counter {}");
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn zip_dir_rejects_non_utf8_names() {
+        let dir = work_dir("non-utf8");
+        write(dir.join(OsStr::from_bytes(b"Counter\xff.sol")), "").unwrap();
+        let err = zip_dir(
+            &dir,
+            Cursor::new(Vec::new()),
+            zip::CompressionMethod::Deflated,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("not a valid UTF-8 file name"));
+        let _ = remove_dir_all(&dir);
+    }
 }
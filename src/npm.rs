@@ -1,12 +1,114 @@
-use crate::db::{insert_invalid_version_into_db, Version};
-use crate::utils::{get_current_working_dir, read_file_to_string};
+use crate::config::{RepositoryConfig, RepositoryEntry};
+use crate::limits::{host_of, Limits, MAX_RATE_LIMITED_ATTEMPTS};
+use crate::source::{Listing, LoadError, MaterializeError, Source, Validator, Validators};
+use crate::utils::{get_current_working_dir, get_job_dir, read_file_to_string};
 use crate::VersionStruct;
-use chrono::DateTime;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use serde_derive::Deserialize;
-use std::fmt::{self};
-use std::process::{Command, Output};
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::{Component, Path, PathBuf};
+use tar::Archive;
 
-pub fn load_repositories() -> Result<Vec<String>, LoadError> {
+const DEFAULT_REGISTRY: &str = "https://registry.npmjs.org";
+
+pub struct NpmSource {
+    client: Client,
+    registry: String,
+}
+
+impl NpmSource {
+    // NPM_REGISTRY points the crawler to another registry, e.g. a local stand-in
+    pub fn new() -> Self {
+        NpmSource {
+            client: Client::new(),
+            registry: std::env::var("NPM_REGISTRY")
+                .unwrap_or(DEFAULT_REGISTRY.to_string())
+                .trim_end_matches("/")
+                .to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Source for NpmSource {
+    fn name(&self) -> &'static str {
+        "npm"
+    }
+
+    fn load_repositories(&self) -> Result<Vec<RepositoryConfig>, LoadError> {
+        load_repositories()
+    }
+
+    async fn retrieve_versions(
+        &self,
+        repository: &RepositoryConfig,
+        limits: &Limits,
+        validators: &mut Validators,
+    ) -> Result<Listing, LoadError> {
+        npm_retrieve_versions(
+            &self.client,
+            limits,
+            &self.registry,
+            &repository.repo,
+            validators,
+        )
+        .await
+    }
+
+    fn materialize_path(&self, repository: &RepositoryConfig, version: &VersionStruct) -> PathBuf {
+        get_job_dir(
+            self.name(),
+            &self.dependency_name(repository),
+            &version.name,
+        )
+    }
+
+    async fn materialize(
+        &self,
+        repository: &RepositoryConfig,
+        version: &VersionStruct,
+        limits: &Limits,
+    ) -> Result<PathBuf, MaterializeError> {
+        let target = self.materialize_path(repository, version);
+        if target.exists() {
+            self.cleanup(&target);
+        }
+        let retrieved =
+            retrieve_version(&self.client, limits, &repository.repo, version, &target).await;
+        if let Err(err) = retrieved {
+            if target.exists() {
+                self.cleanup(&target);
+            }
+            return Err(err);
+        }
+        Ok(target)
+    }
+
+    fn dependency_name(&self, repository: &RepositoryConfig) -> String {
+        repository
+            .name
+            .as_ref()
+            .unwrap_or(&repository.repo)
+            .replace("/", "-")
+            .replace(".", "-")
+    }
+
+    fn cleanup(&self, path: &Path) {
+        if let Err(err) = remove_dir_all(path) {
+            eprintln!("Error removing {}: {}", path.display(), err);
+        }
+    }
+}
+
+pub fn load_repositories() -> Result<Vec<RepositoryConfig>, LoadError> {
     println!("Loading list of repositories for NPM");
     let filename: String = get_current_working_dir()
         .unwrap()
@@ -14,7 +116,9 @@ pub fn load_repositories() -> Result<Vec<String>, LoadError> {
         .to_str()
         .unwrap()
         .to_string();
-    let contents = read_file_to_string(filename.clone()).unwrap();
+    let contents = read_file_to_string(filename.clone()).map_err(|err| LoadError {
+        cause: format!("reading {}: {}", filename, err),
+    })?;
     let data: Data = match toml::from_str(&contents) {
         // If successful, return data as `Data` struct.
         // `d` is a local variable.
@@ -25,125 +129,375 @@ pub fn load_repositories() -> Result<Vec<String>, LoadError> {
             // Write `msg` to `stderr`.
             eprintln!("Unable to load data from repositories.toml");
             // Exit the program with exit code `1`.
-            return Err(LoadError);
+            return Err(LoadError {
+                cause: format!("parsing {}: {}", filename, err),
+            });
         }
     };
 
-    let mut repositories: Vec<String> = Vec::new();
-    data.npm.iter().for_each(|value: &String| {
-        repositories.push(value.clone());
-    });
+    let repositories: Vec<RepositoryConfig> =
+        data.npm.into_iter().map(RepositoryConfig::from).collect();
 
     Ok(repositories)
 }
 
-pub fn npm_retrieve_versions(repository: &String) -> Result<Vec<VersionStruct>, LoadError> {
-    let output: Output = Command::new("npm")
-        .arg("view")
-        .arg(repository)
-        .arg("versions")
-        .output()
-        .expect("failed to execute process");
-    // println!("status: {}", String::from_utf8(output.stdout.clone()).unwrap());
-    let json_string: String = String::from_utf8(output.stdout.clone())
-        .unwrap()
-        .replace("'", "\"");
-
-    let mut versions: Vec<VersionStruct> = Vec::new();
-    if json_string.contains("[") {
-        let versions_string: Vec<String> = serde_json::from_str::<Vec<String>>(json_string.trim())
-            .map_err(|err: serde_json::Error| err)
-            .unwrap();
-        for v in versions_string {
-            versions.push(VersionStruct {
-                name: v,
-                url: "".to_string(),
-            })
-        }
-    } else {
-        versions.push(VersionStruct {
-            name: json_string.trim().to_string(),
-            url: "".to_string(),
+pub async fn npm_retrieve_versions(
+    client: &Client,
+    limits: &Limits,
+    registry: &str,
+    repository: &str,
+    validators: &mut Validators,
+) -> Result<Listing, LoadError> {
+    // scoped packages are requested as @scope%2fname
+    let url = format!("{}/{}", registry, repository.replace("/", "%2f"));
+    let mut request = client.get(&url).header("Accept", "application/json");
+    if let Some(validator) = validators.get(&url) {
+        if let Some(etag) = &validator.etag {
+            request = request.header("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &validator.last_modified {
+            request = request.header("If-Modified-Since", last_modified);
+        }
+    }
+    let response = match send(limits, request).await {
+        Ok(response) => response,
+        Err(err) => {
+            return Err(LoadError {
+                cause: format!("fetching packument: {}", err),
+            });
+        }
+    };
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Listing::Unchanged);
+    }
+    if !response.status().is_success() {
+        return Err(LoadError {
+            cause: format!("fetching packument: {}", response.status()),
         });
     }
-    Ok(versions)
+    let validator = Validator::from_headers(|name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    });
+    let body: String = match response.text().await {
+        Ok(body) => body,
+        Err(err) => {
+            return Err(LoadError {
+                cause: format!("reading packument: {}", err),
+            });
+        }
+    };
+    let packument: Packument = match serde_json::from_str(&body) {
+        Ok(packument) => packument,
+        Err(err) => {
+            return Err(LoadError {
+                cause: format!("parsing packument: {}", err),
+            });
+        }
+    };
+
+    let mut versions: Vec<VersionStruct> = packument
+        .versions
+        .into_iter()
+        .map(|(name, manifest)| VersionStruct {
+            // `time` also holds `created`/`modified` and, for unpublished packages, objects
+            published_at: packument
+                .time
+                .get(&name)
+                .and_then(|time| time.as_str())
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .map(|time| time.with_timezone(&Utc)),
+            name,
+            url: manifest.dist.tarball,
+            integrity: manifest.dist.integrity,
+            shasum: manifest.dist.shasum,
+            ..Default::default()
+        })
+        .collect();
+    // the packument is an unordered map, process versions in publication order
+    versions.sort_by(|a, b| {
+        a.published_at
+            .cmp(&b.published_at)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    validators.clear();
+    if let Some(validator) = validator {
+        validators.insert(url, validator);
+    }
+    Ok(Listing::Changed(versions))
 }
 
-// TODO: multi-threading
-#[allow(dead_code)]
-pub fn check_versions_health(
-    repository: &String,
-    versions: Vec<String>,
-) -> Result<Vec<String>, HealthCheckError> {
-    let mut valid_versions: Vec<String> = Vec::new();
-    for version in versions.iter() {
-        println!("Health check version: {:?}", version);
-        let output: Output = Command::new("npm")
-            .arg("i")
-            .arg(format!("{}@{}", repository, version))
-            .arg("--force")
-            .arg("--dry-run")
-            .output()
-            .expect("failed to execute process");
-        if output.status.success() {
-            valid_versions.push(version.clone());
-        } else {
-            println!("Version {} of {} is not valid", version, repository);
-            insert_invalid_version_into_db(Version {
-                repository: repository.to_string(),
-                version: version.to_string(),
-                last_updated: DateTime::default(),
-            })
-            .unwrap()
-        }
-    }
-    Ok(valid_versions)
-}
-pub fn retrieve_version(
+pub async fn retrieve_version(
+    client: &Client,
+    limits: &Limits,
     repository: &String,
     version: &VersionStruct,
-) -> Result<(), HealthCheckError> {
-    let output: Output = Command::new("npm")
-        .arg("i")
-        .args(["--force", "--prefix . downloaded "])
-        .arg(format!("{}@{}", repository, version.name))
-        .output()
-        .expect("failed to execute process");
-    println!("Result of retrieving version: {:?}", output);
-
-    if output.status.success() {
-        Ok(())
-    } else {
-        println!("Version {} of {} is not valid", version.name, repository);
-        insert_invalid_version_into_db(Version {
-            repository: repository.to_string(),
-            version: version.name.to_string(),
-            last_updated: DateTime::default(),
-        })
-        .unwrap();
-        Err(HealthCheckError)
+    target: &Path,
+) -> Result<(), MaterializeError> {
+    println!("Retrieving version {} of {}", version.name, repository);
+    let result = match download_tarball(client, limits, version).await {
+        Ok(tarball) => match verify_tarball(&tarball, version) {
+            Ok(_) => extract_tarball_blocking(tarball, target)
+                .await
+                .map_err(|err| MaterializeError::Unzip(err.to_string())),
+            // most likely a transfer that went wrong, retried like any other failed download
+            Err(err) => Err(MaterializeError::Download(err)),
+        },
+        Err(err) => Err(MaterializeError::Download(err)),
+    };
+
+    if let Err(err) = &result {
+        println!(
+            "Version {} of {} is not valid: {}",
+            version.name, repository, err
+        );
     }
+    result
+}
+
+async fn download_tarball(
+    client: &Client,
+    limits: &Limits,
+    version: &VersionStruct,
+) -> Result<Vec<u8>, String> {
+    let response = send(limits, client.get(&version.url)).await?;
+    if !response.status().is_success() {
+        return Err(format!("{} returned {}", version.url, response.status()));
+    }
+    let tarball = response.bytes().await.map_err(|err| err.to_string())?;
+    Ok(tarball.to_vec())
+}
+
+// Sends `request` within the rate of its host, sending it again when the rate limit turned it down
+async fn send(limits: &Limits, mut request: RequestBuilder) -> Result<Response, String> {
+    let mut attempts = 1;
+    loop {
+        let retry = request.try_clone();
+        let (client, built) = request.build_split();
+        let built = built.map_err(|err| err.to_string())?;
+        let host = host_of(built.url().as_str());
+        limits.throttle(&host).await?;
+        let response = client.execute(built).await.map_err(|err| err.to_string())?;
+        let rate_limited = limits.observe(&host, response.status().as_u16(), |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        });
+        match retry {
+            Some(retry) if rate_limited && attempts < MAX_RATE_LIMITED_ATTEMPTS => {
+                attempts += 1;
+                request = retry;
+            }
+            _ => return Ok(response),
+        }
+    }
+}
+
+// Checks the tarball against `dist.integrity`, falling back to the legacy `dist.shasum`
+fn verify_tarball(tarball: &[u8], version: &VersionStruct) -> Result<(), String> {
+    if let Some(integrity) = &version.integrity {
+        // an integrity string can hold several space separated `algorithm-base64` hashes
+        for hash in integrity.split_whitespace() {
+            let Some((algorithm, expected)) = hash.split_once("-") else {
+                continue;
+            };
+            let actual = match algorithm {
+                "sha512" => BASE64.encode(Sha512::digest(tarball)),
+                "sha1" => BASE64.encode(Sha1::digest(tarball)),
+                _ => continue,
+            };
+            if actual == expected {
+                return Ok(());
+            }
+            return Err(format!("integrity mismatch for {}", version.url));
+        }
+    }
+    if let Some(shasum) = &version.shasum {
+        if hex::encode(Sha1::digest(tarball)) == shasum.to_lowercase() {
+            return Ok(());
+        }
+        return Err(format!("shasum mismatch for {}", version.url));
+    }
+    Err(format!("no usable checksum for {}", version.url))
+}
+
+// Unpacks the tarball on the blocking pool so that the other crawls keep running meanwhile
+async fn extract_tarball_blocking(tarball: Vec<u8>, target: &Path) -> std::io::Result<()> {
+    let target = target.to_path_buf();
+    tokio::task::spawn_blocking(move || extract_tarball(&tarball, &target))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+// Unpacks an npm tarball into `target`, stripping the top level `package/` directory
+fn extract_tarball(tarball: &[u8], target: &Path) -> std::io::Result<()> {
+    let mut archive = Archive::new(GzDecoder::new(tarball));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        // links could point outside of `target`, packages only need files and directories
+        if !entry_type.is_file() && !entry_type.is_dir() {
+            continue;
+        }
+        let path: PathBuf = entry.path()?.components().skip(1).collect();
+        if path.as_os_str().is_empty()
+            || path
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            continue;
+        }
+        let destination = target.join(&path);
+        if let Some(parent) = destination.parent() {
+            create_dir_all(parent)?;
+        }
+        entry.unpack(&destination)?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct Packument {
+    #[serde(default)]
+    versions: HashMap<String, PackumentVersion>,
+    #[serde(default)]
+    time: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PackumentVersion {
+    dist: Dist,
+}
+
+#[derive(Deserialize, Debug)]
+struct Dist {
+    tarball: String,
+    integrity: Option<String>,
+    shasum: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct Data {
-    npm: Vec<String>,
+    npm: Vec<RepositoryEntry>,
 }
 
-#[derive(Debug, Clone)]
-pub struct LoadError;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use tar::{Builder, EntryType, Header};
 
-#[derive(Debug, Clone)]
-pub struct HealthCheckError;
+    // a gzipped tarball holding `entries`, paths are written as is, even `..` ones
+    fn tarball(entries: &[(&str, EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, entry_type, content) in entries {
+            let mut header = Header::new_old();
+            let name = &mut header.as_old_mut().name;
+            name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *content).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn version(integrity: Option<String>, shasum: Option<String>) -> VersionStruct {
+        VersionStruct {
+            name: "1.0.0".to_string(),
+            url: "http://registry/pkg-1.0.0.tgz".to_string(),
+            integrity,
+            shasum,
+            ..Default::default()
+        }
+    }
 
-impl fmt::Display for LoadError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "file not found")
+    fn work_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crawler-npm-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn verify_tarball_accepts_matching_integrity() {
+        let tarball = b"tarball";
+        let integrity = format!("sha512-{}", BASE64.encode(Sha512::digest(tarball)));
+        assert!(verify_tarball(tarball, &version(Some(integrity), None)).is_ok());
+    }
+
+    #[test]
+    fn verify_tarball_skips_unknown_algorithms() {
+        let tarball = b"tarball";
+        let integrity = format!("md5-abc sha1-{}", BASE64.encode(Sha1::digest(tarball)));
+        assert!(verify_tarball(tarball, &version(Some(integrity), None)).is_ok());
+    }
+
+    #[test]
+    fn verify_tarball_rejects_mismatching_integrity() {
+        let integrity = format!("sha512-{}", BASE64.encode(Sha512::digest(b"other")));
+        let err = verify_tarball(b"tarball", &version(Some(integrity), None)).unwrap_err();
+        assert!(err.contains("integrity mismatch"));
+    }
+
+    #[test]
+    fn verify_tarball_falls_back_to_shasum() {
+        let tarball = b"tarball";
+        let shasum = hex::encode(Sha1::digest(tarball)).to_uppercase();
+        assert!(verify_tarball(tarball, &version(None, Some(shasum))).is_ok());
+        let err = verify_tarball(tarball, &version(None, Some("00".to_string()))).unwrap_err();
+        assert!(err.contains("shasum mismatch"));
+    }
+
+    #[test]
+    fn verify_tarball_requires_a_checksum() {
+        let err = verify_tarball(b"tarball", &version(None, None)).unwrap_err();
+        assert!(err.contains("no usable checksum"));
+    }
+
+    #[test]
+    fn extract_tarball_strips_the_top_level_directory() {
+        let target = work_dir("strip");
+        let tarball = tarball(&[
+            ("package/package.json", EntryType::Regular, b"{}"),
+            (
+                "package/src/Token.sol",
+                EntryType::Regular,
+                b"contract Token {}",
+            ),
+        ]);
+        extract_tarball(&tarball, &target).unwrap();
+        assert_eq!(fs::read(target.join("package.json")).unwrap(), b"{}");
+        assert_eq!(
+            fs::read(target.join("src/Token.sol")).unwrap(),
+            b"contract Token {}"
+        );
+        remove_dir_all(&target).unwrap();
     }
-}
 
-impl fmt::Display for HealthCheckError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "healthcheck failed")
+    #[test]
+    fn extract_tarball_stays_inside_the_target() {
+        let root = work_dir("traversal");
+        let target = root.join("target");
+        let tarball = tarball(&[
+            ("package/../../escaped.txt", EntryType::Regular, b"escaped"),
+            ("package//absolute.txt", EntryType::Regular, b"absolute"),
+            ("package/link", EntryType::Symlink, b""),
+            ("package/kept.txt", EntryType::Regular, b"kept"),
+        ]);
+        extract_tarball(&tarball, &target).unwrap();
+        assert!(!root.join("escaped.txt").exists());
+        assert!(!target.join("link").exists());
+        assert_eq!(fs::read(target.join("kept.txt")).unwrap(), b"kept");
+        let entries: Vec<_> = fs::read_dir(&target).unwrap().collect();
+        assert_eq!(entries.len(), 2);
+        remove_dir_all(&root).unwrap();
     }
 }
@@ -0,0 +1,238 @@
+use crate::config::{
+    load_settings, parse_interval, RepositoryConfig, Settings, DEFAULT_INTERVAL,
+    DEFAULT_MAX_INTERVAL,
+};
+use chrono::{DateTime, Duration, Utc};
+
+// a repository is checked about this many times between two of its releases
+const CHECKS_PER_RELEASE: i32 = 10;
+
+/// Decides when a repository gets checked again.
+///
+/// Repositories with an `interval` in repositories.toml are checked at that interval, or every
+/// repository at the `--interval` given on the command line. The others follow their release
+/// cadence: one that released yesterday is checked every couple of hours, one silent for a year
+/// only every `max_interval`. Failing repositories back off exponentially.
+pub struct Schedule {
+    // used while the release history of a repository is unknown
+    pub default_interval: Duration,
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    // set by `--interval`, which applies to every repository without an `interval` of its own
+    pub overridden: bool,
+}
+
+impl Schedule {
+    // Reads the intervals from repositories.toml, `interval` from the command line wins over it
+    pub fn load(interval: Option<String>) -> Result<Self, String> {
+        let settings = load_settings().map_err(|err| format!("loading settings: {}", err))?;
+        Schedule::from_settings(settings, interval)
+    }
+
+    // The bounds only apply to the interval of repositories.toml, `interval` from the command line
+    // is a fixed interval that doesn't follow release cadences
+    fn from_settings(settings: Settings, interval: Option<String>) -> Result<Self, String> {
+        let configured = settings.interval.unwrap_or(DEFAULT_INTERVAL.to_string());
+        let min_interval = settings.min_interval.unwrap_or(configured.clone());
+        let max_interval = settings
+            .max_interval
+            .unwrap_or(DEFAULT_MAX_INTERVAL.to_string());
+        let (configured_interval, min_interval, max_interval) = match (
+            parse_interval(&configured),
+            parse_interval(&min_interval),
+            parse_interval(&max_interval),
+        ) {
+            (Some(configured_interval), Some(min_interval), Some(max_interval))
+                if min_interval <= configured_interval && configured_interval <= max_interval =>
+            {
+                (configured_interval, min_interval, max_interval)
+            }
+            _ => {
+                return Err(format!(
+                    "invalid intervals {} (min {}, max {})",
+                    configured, min_interval, max_interval
+                ))
+            }
+        };
+        let default_interval = match &interval {
+            Some(interval) => {
+                parse_interval(interval).ok_or(format!("invalid interval {}", interval))?
+            }
+            None => configured_interval,
+        };
+        Ok(Schedule {
+            default_interval,
+            min_interval,
+            max_interval,
+            overridden: interval.is_some(),
+        })
+    }
+
+    // The interval `repository` is checked at and whether it is fixed, i.e. set by its `interval` or
+    // `--interval` instead of following its releases
+    pub fn interval(&self, repository: &RepositoryConfig) -> Result<(Duration, bool), String> {
+        let interval = repository.interval(self.default_interval)?;
+        Ok((interval, repository.interval.is_some() || self.overridden))
+    }
+
+    pub fn next_check(
+        &self,
+        repository: &RepositoryConfig,
+        last_release_at: Option<DateTime<Utc>>,
+        consecutive_failures: u32,
+    ) -> DateTime<Utc> {
+        let now = Utc::now();
+        let interval = if consecutive_failures > 0 {
+            let backoff = 1 << consecutive_failures.min(16).saturating_sub(1);
+            (self.min_interval * backoff).min(self.max_interval)
+        } else if repository.interval.is_some() || self.overridden {
+            repository
+                .interval(self.default_interval)
+                .unwrap_or(self.default_interval)
+        } else {
+            match last_release_at {
+                Some(last_release_at) => ((now - last_release_at) / CHECKS_PER_RELEASE)
+                    .clamp(self.min_interval, self.max_interval),
+                None => self.default_interval,
+            }
+        };
+        now + interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the settings of the repositories.toml shipped with the crawler
+    fn shipped_settings() -> Settings {
+        toml::from_str(include_str!("../repositories.toml")).unwrap()
+    }
+
+    #[test]
+    fn load_accepts_any_interval_override() {
+        for interval in ["30m", "1h", "2w"] {
+            let schedule =
+                Schedule::from_settings(shipped_settings(), Some(interval.to_string())).unwrap();
+            assert_eq!(Some(schedule.default_interval), parse_interval(interval));
+            assert!(schedule.overridden);
+            assert_eq!(schedule.min_interval, Duration::hours(1));
+            assert_eq!(schedule.max_interval, Duration::days(7));
+        }
+        assert!(Schedule::from_settings(shipped_settings(), Some("1h30m".to_string())).is_err());
+    }
+
+    #[test]
+    fn load_checks_the_configured_bounds() {
+        let schedule = Schedule::from_settings(shipped_settings(), None).unwrap();
+        assert_eq!(schedule.default_interval, Duration::hours(1));
+        assert!(!schedule.overridden);
+        let settings = Settings {
+            interval: Some("30m".to_string()),
+            min_interval: Some("1h".to_string()),
+            ..Default::default()
+        };
+        assert!(Schedule::from_settings(settings, None).is_err());
+        let settings = Settings {
+            interval: Some("2w".to_string()),
+            ..Default::default()
+        };
+        assert!(Schedule::from_settings(settings, None).is_err());
+        // `min_interval` defaults to `interval`
+        let settings = Settings {
+            interval: Some("30m".to_string()),
+            ..Default::default()
+        };
+        let schedule = Schedule::from_settings(settings, None).unwrap();
+        assert_eq!(schedule.min_interval, Duration::minutes(30));
+    }
+
+    fn schedule(overridden: bool) -> Schedule {
+        Schedule {
+            default_interval: Duration::hours(1),
+            min_interval: Duration::minutes(30),
+            max_interval: Duration::days(7),
+            overridden,
+        }
+    }
+
+    fn repository(interval: Option<&str>) -> RepositoryConfig {
+        RepositoryConfig {
+            repo: "Uniswap/v4-core".to_string(),
+            interval: interval.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    // how far from now `next_check` schedules the repository, to the minute
+    fn next_check_in(
+        schedule: &Schedule,
+        repository: &RepositoryConfig,
+        last_release_at: Option<DateTime<Utc>>,
+        consecutive_failures: u32,
+    ) -> i64 {
+        let next_check = schedule.next_check(repository, last_release_at, consecutive_failures);
+        (next_check - Utc::now() + Duration::seconds(1)).num_minutes()
+    }
+
+    #[test]
+    fn interval_is_fixed_by_the_repository_or_the_override() {
+        assert_eq!(
+            schedule(false).interval(&repository(None)),
+            Ok((Duration::hours(1), false))
+        );
+        assert_eq!(
+            schedule(false).interval(&repository(Some("2h"))),
+            Ok((Duration::hours(2), true))
+        );
+        assert_eq!(
+            schedule(true).interval(&repository(None)),
+            Ok((Duration::hours(1), true))
+        );
+        assert!(schedule(false)
+            .interval(&repository(Some("1h30m")))
+            .is_err());
+    }
+
+    #[test]
+    fn next_check_follows_the_release_cadence() {
+        let schedule = schedule(false);
+        let repository = repository(None);
+        assert_eq!(next_check_in(&schedule, &repository, None, 0), 60);
+        let released = Utc::now() - Duration::days(10);
+        assert_eq!(
+            next_check_in(&schedule, &repository, Some(released), 0),
+            24 * 60
+        );
+        // clamped to the bounds
+        let released = Utc::now() - Duration::hours(1);
+        assert_eq!(next_check_in(&schedule, &repository, Some(released), 0), 30);
+        let released = Utc::now() - Duration::days(1000);
+        assert_eq!(
+            next_check_in(&schedule, &repository, Some(released), 0),
+            7 * 24 * 60
+        );
+    }
+
+    #[test]
+    fn next_check_uses_fixed_intervals() {
+        let released = Some(Utc::now() - Duration::days(10));
+        assert_eq!(
+            next_check_in(&schedule(false), &repository(Some("2h")), released, 0),
+            120
+        );
+        assert_eq!(
+            next_check_in(&schedule(true), &repository(None), released, 0),
+            60
+        );
+    }
+
+    #[test]
+    fn next_check_backs_off_failures() {
+        let schedule = schedule(false);
+        let repository = repository(Some("2h"));
+        assert_eq!(next_check_in(&schedule, &repository, None, 1), 30);
+        assert_eq!(next_check_in(&schedule, &repository, None, 3), 120);
+        assert_eq!(next_check_in(&schedule, &repository, None, 40), 7 * 24 * 60);
+    }
+}
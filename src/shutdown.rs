@@ -0,0 +1,60 @@
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+
+/// Set once SIGINT or SIGTERM is received, the crawl stops after the version in flight.
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    // resolves once a shutdown has been requested
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // register before checking the flag so a request in between isn't missed
+        notified.as_mut().enable();
+        if self.is_requested() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+// Requests a shutdown on the first SIGINT or SIGTERM and exits right away on the second one
+pub fn listen_for_signals(shutdown: Arc<Shutdown>) {
+    tokio::spawn(async move {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(err) => {
+                eprintln!("Error listening for SIGTERM: {}", err);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            if shutdown.is_requested() {
+                eprintln!("Aborting");
+                exit(130);
+            }
+            eprintln!("Shutting down after the current version, signal again to abort");
+            shutdown.request();
+        }
+    });
+}
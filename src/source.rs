@@ -0,0 +1,113 @@
+use crate::config::RepositoryConfig;
+use crate::limits::Limits;
+use crate::VersionStruct;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt::{self};
+use std::path::{Path, PathBuf};
+
+/// A registry the crawler discovers dependencies from (npm, GitHub, ...).
+#[async_trait]
+pub trait Source: Send + Sync {
+    // the key of the source in repositories.toml, also used in logs
+    fn name(&self) -> &'static str;
+
+    fn load_repositories(&self) -> Result<Vec<RepositoryConfig>, LoadError>;
+
+    // every request goes through `limits` so that a host is never hit faster than it allows.
+    // The listings are only downloaded when they changed since `validators` were stored, which
+    // are then replaced by the ones of the new listings.
+    async fn retrieve_versions(
+        &self,
+        repository: &RepositoryConfig,
+        limits: &Limits,
+        validators: &mut Validators,
+    ) -> Result<Listing, LoadError>;
+
+    // the directory `materialize` puts the content of `version` in
+    fn materialize_path(&self, repository: &RepositoryConfig, version: &VersionStruct) -> PathBuf;
+
+    // makes the content of `version` available on disk and returns the directory to push
+    async fn materialize(
+        &self,
+        repository: &RepositoryConfig,
+        version: &VersionStruct,
+        limits: &Limits,
+    ) -> Result<PathBuf, MaterializeError>;
+
+    // the name the dependency is published under on Soldeer
+    fn dependency_name(&self, repository: &RepositoryConfig) -> String;
+
+    // removes what `materialize` wrote to `path` once the version has been published
+    fn cleanup(&self, _path: &Path) {}
+
+    // requests sent with every credential of the source since the last call, for the run report
+    fn take_usage(&self) -> Vec<Usage> {
+        Vec::new()
+    }
+}
+
+/// Requests a source sent with one of its credentials, e.g. a GitHub token.
+#[derive(Debug, Clone)]
+pub struct Usage {
+    pub credential: String,
+    pub requests: usize,
+    // quota left as last reported by the registry
+    pub remaining: Option<u64>,
+}
+
+pub enum Listing {
+    // every listing still has the validators of the last crawl
+    Unchanged,
+    Changed(Vec<VersionStruct>),
+}
+
+/// What a registry sent along with a listing to tell whether it changed since.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validator {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validator {
+    // Reads the `etag` and `last-modified` headers of a response, if it has any
+    pub fn from_headers(header: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let validator = Validator {
+            etag: header("etag"),
+            last_modified: header("last-modified"),
+        };
+        if validator == Validator::default() {
+            return None;
+        }
+        Some(validator)
+    }
+}
+
+// the validators of the listings of a repository, by URL
+pub type Validators = HashMap<String, Validator>;
+
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    pub cause: String,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.cause)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MaterializeError {
+    Download(String),
+    Unzip(String),
+}
+
+impl fmt::Display for MaterializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaterializeError::Download(cause) => write!(f, "download failed: {}", cause),
+            MaterializeError::Unzip(cause) => write!(f, "unzip failed: {}", cause),
+        }
+    }
+}
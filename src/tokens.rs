@@ -0,0 +1,237 @@
+use crate::source::Usage;
+use crate::utils::read_file_to_string;
+use chrono::Utc;
+use octocrab::{Octocrab, OctocrabBuilder};
+use std::cmp::Reverse;
+use std::env;
+use std::sync::Mutex;
+
+/// The GitHub clients of a crawl, one per token, shared by every repository.
+///
+/// Tokens come from GITHUB_TOKEN, the comma separated GITHUB_TOKENS and the file GITHUB_TOKENS_FILE
+/// points to (one token per line). Every request goes to the token with the most quota left, so a
+/// large backfill only waits once all of them are exhausted.
+pub struct TokenPool {
+    clients: Vec<Client>,
+    usage: Mutex<Vec<TokenUsage>>,
+}
+
+struct Client {
+    // never the token itself, only enough to tell the tokens apart in logs
+    label: String,
+    // also sent along with the downloads of zipballs, which don't go through octocrab
+    token: Option<String>,
+    octocrab: Octocrab,
+}
+
+#[derive(Default, Clone)]
+struct TokenUsage {
+    // sent since the last report
+    requests: usize,
+    // from the `x-ratelimit-*` headers of the last response
+    remaining: Option<u64>,
+    reset_at: Option<i64>,
+}
+
+impl TokenPool {
+    pub fn from_env() -> Self {
+        let tokens = load_tokens();
+        let clients: Vec<Client> = if tokens.is_empty() {
+            eprintln!("Warning: no GitHub token set. Using unauthenticated API access (lower rate limits)");
+            eprintln!(
+                "Set GITHUB_TOKEN, GITHUB_TOKENS or GITHUB_TOKENS_FILE for higher rate limits"
+            );
+            vec![Client {
+                label: "unauthenticated".to_string(),
+                token: None,
+                octocrab: OctocrabBuilder::new()
+                    .build()
+                    .expect("Failed to build Octocrab instance"),
+            }]
+        } else {
+            tokens
+                .into_iter()
+                .enumerate()
+                .map(|(index, token)| Client {
+                    label: format!("token {} (...{})", index + 1, token_suffix(&token)),
+                    token: Some(token.clone()),
+                    octocrab: OctocrabBuilder::new()
+                        .personal_token(token)
+                        .build()
+                        .expect("Failed to build Octocrab instance with token"),
+                })
+                .collect()
+        };
+        println!("Using {} GitHub clients", clients.len());
+        TokenPool {
+            usage: Mutex::new(vec![TokenUsage::default(); clients.len()]),
+            clients,
+        }
+    }
+
+    // Picks the token with the most quota left, or the one resetting first when all are exhausted
+    pub fn pick(&self) -> (usize, &Octocrab) {
+        let now = Utc::now().timestamp();
+        let mut usage = self.usage.lock().unwrap();
+        let available = |token: &TokenUsage| {
+            token.remaining != Some(0) || token.reset_at.is_none_or(|reset_at| reset_at <= now)
+        };
+        let index = match usage
+            .iter()
+            .enumerate()
+            .filter(|(_, token)| available(token))
+            // a token that hasn't been used yet has its whole quota left, ties go to the first one
+            .min_by_key(|(_, token)| Reverse(token.remaining.unwrap_or(u64::MAX)))
+        {
+            Some((index, _)) => index,
+            None => usage
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, token)| token.reset_at)
+                .map(|(index, _)| index)
+                .unwrap_or_default(),
+        };
+        usage[index].requests += 1;
+        (index, &self.clients[index].octocrab)
+    }
+
+    // The token at `index`, none when GitHub is accessed without one
+    pub fn token(&self, index: usize) -> Option<&str> {
+        self.clients[index].token.as_deref()
+    }
+
+    // The key the rate limiter tracks a token under, each token has its own quota
+    pub fn limiter_key(&self, index: usize, host: &str) -> String {
+        format!("{} with {}", host, self.clients[index].label)
+    }
+
+    // Remembers the quota a response reported for the token at `index`
+    pub fn record(&self, index: usize, remaining: Option<u64>, reset_at: Option<i64>) {
+        let mut usage = self.usage.lock().unwrap();
+        if remaining.is_some() {
+            usage[index].remaining = remaining;
+            usage[index].reset_at = reset_at;
+        }
+    }
+
+    // Requests sent with every token since the last call
+    pub fn take_usage(&self) -> Vec<Usage> {
+        let mut usage = self.usage.lock().unwrap();
+        self.clients
+            .iter()
+            .zip(usage.iter_mut())
+            .filter(|(_, token)| token.requests > 0)
+            .map(|(client, token)| Usage {
+                credential: format!("github {}", client.label),
+                requests: std::mem::take(&mut token.requests),
+                remaining: token.remaining,
+            })
+            .collect()
+    }
+}
+
+fn load_tokens() -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    if let Ok(token) = env::var("GITHUB_TOKEN") {
+        tokens.push(token);
+    }
+    if let Ok(list) = env::var("GITHUB_TOKENS") {
+        tokens.extend(list.split(',').map(|token| token.to_string()));
+    }
+    if let Ok(filename) = env::var("GITHUB_TOKENS_FILE") {
+        // blank lines and `#` comments are skipped
+        if let Ok(contents) = read_file_to_string(filename) {
+            tokens.extend(
+                contents
+                    .lines()
+                    .filter(|line| !line.trim_start().starts_with('#'))
+                    .map(|line| line.to_string()),
+            );
+        }
+    }
+
+    let mut unique: Vec<String> = Vec::new();
+    for token in tokens.into_iter().map(|token| token.trim().to_string()) {
+        if !token.is_empty() && !unique.contains(&token) {
+            unique.push(token);
+        }
+    }
+    unique
+}
+
+fn token_suffix(token: &str) -> &str {
+    let start = token
+        .char_indices()
+        .rev()
+        .nth(3)
+        .map(|(index, _)| index)
+        .unwrap_or(0);
+    &token[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(tokens: &[&str]) -> TokenPool {
+        let clients: Vec<Client> = tokens
+            .iter()
+            .enumerate()
+            .map(|(index, token)| Client {
+                label: format!("token {} (...{})", index + 1, token_suffix(token)),
+                token: Some(token.to_string()),
+                octocrab: OctocrabBuilder::new().build().unwrap(),
+            })
+            .collect();
+        TokenPool {
+            usage: Mutex::new(vec![TokenUsage::default(); clients.len()]),
+            clients,
+        }
+    }
+
+    #[tokio::test]
+    async fn picks_the_token_with_the_most_quota_left() {
+        let tokens = pool(&["ghp_first", "ghp_second"]);
+        assert_eq!(tokens.pick().0, 0);
+        tokens.record(0, Some(10), Some(Utc::now().timestamp() + 60));
+        assert_eq!(tokens.pick().0, 1);
+        assert_eq!(tokens.token(1), Some("ghp_second"));
+        tokens.record(1, Some(5), Some(Utc::now().timestamp() + 60));
+        assert_eq!(tokens.pick().0, 0);
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_first_reset_once_exhausted() {
+        let tokens = pool(&["ghp_first", "ghp_second"]);
+        let now = Utc::now().timestamp();
+        tokens.record(0, Some(0), Some(now + 600));
+        tokens.record(1, Some(0), Some(now + 60));
+        assert_eq!(tokens.pick().0, 1);
+        // a token whose quota was reset is available again
+        tokens.record(0, Some(0), Some(now - 1));
+        assert_eq!(tokens.pick().0, 0);
+    }
+
+    #[tokio::test]
+    async fn reports_the_requests_of_every_token() {
+        let tokens = pool(&["ghp_first", "ghp_second"]);
+        tokens.pick();
+        tokens.record(0, Some(4999), None);
+        tokens.pick();
+        let usage = tokens.take_usage();
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[0].credential, "github token 1 (...irst)");
+        assert_eq!(usage[0].requests, 1);
+        assert_eq!(usage[0].remaining, Some(4999));
+        // the second token had its whole quota left
+        assert_eq!(usage[1].credential, "github token 2 (...cond)");
+        assert_eq!(usage[1].remaining, None);
+        assert!(tokens.take_usage().is_empty());
+    }
+
+    #[test]
+    fn token_suffix_keeps_the_last_four_characters() {
+        assert_eq!(token_suffix("ghp_abcdef"), "cdef");
+        assert_eq!(token_suffix("abc"), "abc");
+    }
+}
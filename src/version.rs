@@ -0,0 +1,403 @@
+use crate::config::RepositoryConfig;
+use crate::VersionStruct;
+use regex::Regex;
+use semver::Version;
+use std::collections::HashMap;
+
+// a dotted version with optional pre-release and build metadata, e.g. `1.2.3-rc.1+build.5`
+const FULL_VERSION: &str = r"^[vV]?(\d+(?:\.\d+)+(?:-[0-9A-Za-z.\-]+)?(?:\+[0-9A-Za-z.\-]+)?)$";
+// a bare major version such as solmate's `v7`
+const MAJOR_VERSION: &str = r"^[vV]?(\d+)$";
+
+/// Turns git tags and release titles into the versions published on Soldeer.
+///
+/// Without any configuration it understands `v1.2.3`, `package@1.2.3`, `contracts-v1.2.3`,
+/// `release/1.2` and titles such as `Release 1.2`. Repositories can narrow this down with a
+/// `tag_prefix` that is stripped before parsing, or replace it with a `tag_pattern` regex whose
+/// `version` (or first) capture group is the version.
+pub struct TagParser {
+    prefix: Option<String>,
+    pattern: Option<Regex>,
+    full_version: Regex,
+    major_version: Regex,
+}
+
+impl TagParser {
+    pub fn new(repository: &RepositoryConfig) -> Result<Self, regex::Error> {
+        let pattern = match &repository.tag_pattern {
+            Some(pattern) => Some(Regex::new(pattern)?),
+            None => None,
+        };
+        Ok(TagParser {
+            prefix: repository.tag_prefix.clone(),
+            pattern,
+            full_version: Regex::new(FULL_VERSION).unwrap(),
+            major_version: Regex::new(MAJOR_VERSION).unwrap(),
+        })
+    }
+
+    pub fn parse(&self, tag: &str) -> Option<String> {
+        let tag = tag.trim();
+        if let Some(pattern) = &self.pattern {
+            let captures = pattern.captures(tag)?;
+            let version = captures.name("version").or_else(|| captures.get(1))?;
+            return Some(version.as_str().trim().to_string());
+        }
+
+        let mut candidate = tag;
+        if let Some(prefix) = &self.prefix {
+            candidate = candidate.strip_prefix(prefix.as_str())?;
+        }
+        // `package@1.2.3` and `release/1.2`
+        if let Some((_, version)) = candidate.rsplit_once("@") {
+            candidate = version;
+        }
+        if let Some((_, version)) = candidate.rsplit_once("/") {
+            candidate = version;
+        }
+        // release titles such as `Release 1.2` or `v1.0.0 beta`
+        let candidate = candidate
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join("-");
+
+        self.find(&candidate, &self.full_version)
+            .or_else(|| self.find(&candidate, &self.major_version))
+    }
+
+    // the version starting at the leftmost word boundary of `candidate` matching `regex`.
+    // A boundary right after a number doesn't count, so `2023-01-01` isn't read as version `01`.
+    fn find(&self, candidate: &str, regex: &Regex) -> Option<String> {
+        let mut before_previous: Option<char> = None;
+        let mut previous: Option<char> = None;
+        for (index, character) in candidate.char_indices() {
+            let boundary = previous.is_none_or(|previous| !previous.is_ascii_alphanumeric())
+                && before_previous.is_none_or(|before| !before.is_ascii_digit());
+            if boundary {
+                if let Some(captures) = regex.captures(&candidate[index..]) {
+                    return Some(captures[1].to_string());
+                }
+            }
+            before_previous = previous;
+            previous = Some(character);
+        }
+        None
+    }
+}
+
+/// Per-repository policy deciding which discovered versions get crawled.
+pub struct VersionFilter {
+    exclude_prereleases: bool,
+    min_version: Option<Version>,
+    latest_per_major: Option<usize>,
+    deny_pattern: Option<Regex>,
+}
+
+impl VersionFilter {
+    pub fn new(repository: &RepositoryConfig) -> Result<Self, String> {
+        let min_version = match &repository.min_version {
+            Some(min_version) => match parse_semver(min_version) {
+                Some(version) => Some(version),
+                None => return Err(format!("invalid min_version {}", min_version)),
+            },
+            None => None,
+        };
+        let deny_pattern = match &repository.deny_pattern {
+            Some(pattern) => match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(err) => return Err(format!("invalid deny_pattern {}", err)),
+            },
+            None => None,
+        };
+        Ok(VersionFilter {
+            exclude_prereleases: repository.exclude_prereleases,
+            min_version,
+            latest_per_major: repository.latest_per_major,
+            deny_pattern,
+        })
+    }
+
+    // Orders `versions` oldest to newest and drops the ones excluded by the policy.
+    // Versions that are not semver (e.g. branch snapshots) can't be compared, they are kept
+    // after the others unless `deny_pattern` matches them.
+    pub fn apply(&self, versions: Vec<VersionStruct>) -> Vec<VersionStruct> {
+        let mut parsed: Vec<(Version, VersionStruct)> = Vec::new();
+        let mut unparsed: Vec<VersionStruct> = Vec::new();
+        for version in versions {
+            if self
+                .deny_pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.is_match(&version.name))
+            {
+                continue;
+            }
+            match parse_semver(&version.name) {
+                Some(semver) => parsed.push((semver, version)),
+                None => unparsed.push(version),
+            }
+        }
+
+        parsed.retain(|(semver, _)| {
+            (!self.exclude_prereleases || semver.pre.is_empty())
+                && self.min_version.as_ref().is_none_or(|min| semver >= min)
+        });
+        parsed.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        if let Some(latest) = self.latest_per_major {
+            let mut per_major: HashMap<u64, usize> = HashMap::new();
+            for (semver, _) in parsed.iter() {
+                *per_major.entry(semver.major).or_insert(0) += 1;
+            }
+            // walking oldest to newest, skip until only `latest` are left in the major
+            parsed.retain(|(semver, _)| {
+                let remaining = per_major.get_mut(&semver.major).unwrap();
+                *remaining -= 1;
+                *remaining < latest
+            });
+        }
+
+        parsed
+            .into_iter()
+            .map(|(_, version)| version)
+            .chain(unparsed)
+            .collect()
+    }
+}
+
+// Parses `version` as semver, padding versions such as `7` or `1.2` with zeros
+pub fn parse_semver(version: &str) -> Option<Version> {
+    if let Ok(semver) = Version::parse(version) {
+        return Some(semver);
+    }
+    let split = version.find(['-', '+']).unwrap_or(version.len());
+    let (core, rest) = version.split_at(split);
+    let parts = core.split(".").count();
+    if core.is_empty() || parts > 2 {
+        return None;
+    }
+    let padding = ".0".repeat(3 - parts);
+    Version::parse(&format!("{}{}{}", core, padding, rest)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parser(tag_prefix: Option<&str>, tag_pattern: Option<&str>) -> TagParser {
+        TagParser::new(&RepositoryConfig {
+            tag_prefix: tag_prefix.map(str::to_string),
+            tag_pattern: tag_pattern.map(str::to_string),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn parse(tag: &str) -> Option<String> {
+        parser(None, None).parse(tag)
+    }
+
+    #[test]
+    fn parses_common_tag_styles() {
+        assert_eq!(parse("v1.2.3").as_deref(), Some("1.2.3"));
+        assert_eq!(parse("1.2.3").as_deref(), Some("1.2.3"));
+        assert_eq!(parse("package@1.2.3").as_deref(), Some("1.2.3"));
+        assert_eq!(parse("@scope/package@1.2.3").as_deref(), Some("1.2.3"));
+        assert_eq!(parse("contracts-v1.2.3").as_deref(), Some("1.2.3"));
+        assert_eq!(parse("release/1.2").as_deref(), Some("1.2"));
+        assert_eq!(
+            parse("v4.0.0-rc.1+build.5").as_deref(),
+            Some("4.0.0-rc.1+build.5")
+        );
+        assert_eq!(parse("v7").as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn parses_release_titles() {
+        assert_eq!(parse("Release 1.2").as_deref(), Some("1.2"));
+        assert_eq!(parse("  v1.0.0 beta ").as_deref(), Some("1.0.0-beta"));
+        assert_eq!(parse("Solmate v7").as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn rejects_tags_without_a_version() {
+        assert_eq!(parse("latest"), None);
+        assert_eq!(parse("nightly-build"), None);
+        assert_eq!(parse("v1.2.3.foo"), None);
+        assert_eq!(parse("audit2"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn rejects_dated_tags() {
+        assert_eq!(parse("2023-01-01"), None);
+        assert_eq!(parse("snapshot-2023-01-01"), None);
+        assert_eq!(parse("release/2024-06"), None);
+    }
+
+    #[test]
+    fn strips_the_tag_prefix() {
+        let parser = parser(Some("core-"), None);
+        assert_eq!(parser.parse("core-v1.2.3").as_deref(), Some("1.2.3"));
+        assert_eq!(parser.parse("periphery-v1.2.3"), None);
+        assert_eq!(parser.parse("v1.2.3"), None);
+    }
+
+    #[test]
+    fn tag_pattern_uses_the_version_group() {
+        let parser = parser(
+            None,
+            Some(r"^build-(?P<build>\d+)-(?P<version>\d+\.\d+\.\d+)$"),
+        );
+        assert_eq!(parser.parse("build-42-1.2.3").as_deref(), Some("1.2.3"));
+        assert_eq!(parser.parse("v1.2.3"), None);
+    }
+
+    #[test]
+    fn tag_pattern_falls_back_to_the_first_group() {
+        let parser = parser(None, Some(r"^deploy_(\d+\.\d+)$"));
+        assert_eq!(parser.parse("deploy_2.1").as_deref(), Some("2.1"));
+        assert_eq!(parser.parse("deploy_x"), None);
+    }
+
+    #[test]
+    fn tag_pattern_replaces_the_default_parsing() {
+        let parser = parser(Some("ignored-"), Some(r"^(\d+)$"));
+        assert_eq!(parser.parse("3").as_deref(), Some("3"));
+        assert_eq!(parser.parse("v1.2.3"), None);
+    }
+
+    fn versions(names: &[&str]) -> Vec<VersionStruct> {
+        names
+            .iter()
+            .map(|name| VersionStruct {
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn names(versions: Vec<VersionStruct>) -> Vec<String> {
+        versions.into_iter().map(|version| version.name).collect()
+    }
+
+    fn filter(repository: RepositoryConfig) -> VersionFilter {
+        VersionFilter::new(&repository).unwrap()
+    }
+
+    #[test]
+    fn filter_orders_oldest_to_newest_with_unparsed_last() {
+        let filter = filter(RepositoryConfig::default());
+        let filtered = filter.apply(versions(&["2.0.0", "main-abc123", "1.10.0", "1.2", "7"]));
+        assert_eq!(
+            names(filtered),
+            vec!["1.2", "1.10.0", "2.0.0", "7", "main-abc123"]
+        );
+    }
+
+    #[test]
+    fn filter_excludes_prereleases() {
+        let filter = filter(RepositoryConfig {
+            exclude_prereleases: true,
+            ..Default::default()
+        });
+        let filtered = filter.apply(versions(&["1.0.0-rc.1", "1.0.0", "1.1.0-beta", "main"]));
+        assert_eq!(names(filtered), vec!["1.0.0", "main"]);
+    }
+
+    #[test]
+    fn filter_drops_versions_below_min_version() {
+        let filter = filter(RepositoryConfig {
+            min_version: Some("1.2".to_string()),
+            ..Default::default()
+        });
+        let filtered = filter.apply(versions(&["1.1.9", "1.2.0", "1.2.0-rc.1", "2"]));
+        assert_eq!(names(filtered), vec!["1.2.0", "2"]);
+    }
+
+    #[test]
+    fn filter_keeps_the_latest_per_major() {
+        let filter = filter(RepositoryConfig {
+            latest_per_major: Some(2),
+            ..Default::default()
+        });
+        let filtered = filter.apply(versions(&[
+            "1.0.0", "2.0.0", "1.1.0", "1.2.0", "3.0.0", "2.1.0", "1.3.0", "2.2.0",
+        ]));
+        assert_eq!(
+            names(filtered),
+            vec!["1.2.0", "1.3.0", "2.1.0", "2.2.0", "3.0.0"]
+        );
+    }
+
+    #[test]
+    fn filter_keeps_nothing_with_zero_per_major() {
+        let filter = filter(RepositoryConfig {
+            latest_per_major: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(
+            names(filter.apply(versions(&["1.0.0", "main"]))),
+            vec!["main"]
+        );
+    }
+
+    #[test]
+    fn filter_applies_deny_pattern_to_every_version() {
+        let filter = filter(RepositoryConfig {
+            deny_pattern: Some("^0\\.|-audit$".to_string()),
+            ..Default::default()
+        });
+        let filtered = filter.apply(versions(&["0.9.0", "1.0.0", "main-audit", "main"]));
+        assert_eq!(names(filtered), vec!["1.0.0", "main"]);
+    }
+
+    #[test]
+    fn filter_rejects_invalid_settings() {
+        assert!(VersionFilter::new(&RepositoryConfig {
+            min_version: Some("latest".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(VersionFilter::new(&RepositoryConfig {
+            deny_pattern: Some("(".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn parse_semver_pads_short_versions() {
+        assert_eq!(parse_semver("7"), Some(Version::new(7, 0, 0)));
+        assert_eq!(parse_semver("1.2"), Some(Version::new(1, 2, 0)));
+        assert_eq!(parse_semver("1.2.3"), Some(Version::new(1, 2, 3)));
+        assert_eq!(
+            parse_semver("1.2-rc.1"),
+            Some(Version::parse("1.2.0-rc.1").unwrap())
+        );
+        assert_eq!(
+            parse_semver("7+build"),
+            Some(Version::parse("7.0.0+build").unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_semver_rejects_non_versions() {
+        assert_eq!(parse_semver(""), None);
+        assert_eq!(parse_semver("main"), None);
+        assert_eq!(parse_semver("1.2.3.4"), None);
+        assert_eq!(parse_semver("-rc.1"), None);
+        assert_eq!(parse_semver("v1.2"), None);
+    }
+
+    #[test]
+    fn find_starts_at_a_word_boundary() {
+        let parser = parser(None, None);
+        let full_version = Regex::new(FULL_VERSION).unwrap();
+        assert_eq!(
+            parser.find("contracts-v1.2.3", &full_version).as_deref(),
+            Some("1.2.3")
+        );
+        assert_eq!(parser.find("abc1.2.3", &full_version), None);
+        assert_eq!(parser.find("1-1.2.3", &full_version), None);
+    }
+}